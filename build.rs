@@ -0,0 +1,42 @@
+//! Embeds the git commit the crate is built from as `GAFF_BUILD_HASH`, so
+//! peers running different builds of the same version can tell them apart.
+
+use std::{path::Path, process::Command};
+
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|output| output.trim().to_string())
+}
+
+fn main() {
+    let hash = git(&["rev-parse", "--short=12", "HEAD"]).unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GAFF_BUILD_HASH={hash}");
+
+    // not built from a git checkout, e.g. from a published crate
+    let (Some(git_dir), Some(common_dir)) = (
+        git(&["rev-parse", "--git-dir"]),
+        git(&["rev-parse", "--git-common-dir"]),
+    ) else {
+        println!("cargo:rerun-if-changed=build.rs");
+        return;
+    };
+
+    // A new commit moves HEAD or the branch it points to, which is either a
+    // loose ref or in packed-refs. In worktrees, HEAD is in the worktree's git
+    // dir, and the refs are shared with the main checkout.
+    let git_dir = Path::new(&git_dir);
+    let common_dir = Path::new(&common_dir);
+    let mut watched = vec![git_dir.join("HEAD"), common_dir.join("packed-refs")];
+    if let Some(head_ref) = git(&["symbolic-ref", "-q", "HEAD"]) {
+        watched.push(common_dir.join(head_ref));
+    }
+    // cargo reruns every build for paths that don't exist
+    for path in watched.iter().filter(|path| path.exists()) {
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
+}
@@ -1,7 +1,18 @@
+//! Command line arguments, layered on top of a config file
+//!
+//! Settings are taken from, in order of precedence: command line flags,
+//! `GAFF_*` environment variables, a TOML config file and the defaults. On the
+//! web, they're read from the query string instead.
+
+use crate::netsim::{Percent, SimDuration};
 use bevy::prelude::*;
-use clap::Parser;
+use bevy_xpbd_2d::math::Scalar;
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
 use serde::Deserialize;
-use std::ffi::OsString;
+use std::{ffi::OsString, path::PathBuf};
+
+/// Read when it exists and no other config file is given
+pub const DEFAULT_CONFIG: &str = "gaff.toml";
 
 #[derive(Parser, Debug, Clone, Deserialize, Resource)]
 #[serde(default)]
@@ -11,14 +22,114 @@ use std::ffi::OsString;
     rename_all_env = "screaming-snake"
 )]
 pub struct Args {
-    #[clap(long, default_value = "ws://127.0.0.1:3536")]
+    #[clap(long, default_value = "ws://127.0.0.1:3536", env = "GAFF_MATCHBOX")]
     pub matchbox: String,
 
-    #[clap(long)]
+    #[clap(long, env = "GAFF_ROOM")]
     pub room: Option<String>,
 
-    #[clap(long, short, default_value = "2")]
+    /// The maximum number of players, others can spectate or wait for a free slot
+    #[clap(long, short, default_value = "2", env = "GAFF_PLAYERS")]
     pub players: usize,
+
+    /// The number of players the host can start early with, defaults to `--players`
+    #[clap(long, env = "GAFF_MIN_PLAYERS")]
+    pub min_players: Option<usize>,
+
+    /// The name shown to other players, can also be changed in the lobby
+    #[clap(long, env = "GAFF_NAME")]
+    pub name: Option<String>,
+
+    /// Watch the match in `--room` instead of playing
+    #[clap(long, requires = "room", env = "GAFF_SPECTATE")]
+    pub spectate: bool,
+
+    /// Run the simulation without a window or renderer
+    #[clap(long, env = "GAFF_HEADLESS")]
+    pub headless: bool,
+
+    /// Record confirmed inputs and checksums to a replay file
+    #[clap(long, env = "GAFF_RECORD")]
+    pub record: Option<PathBuf>,
+
+    /// Play back a replay file instead of joining a match
+    #[clap(long, env = "GAFF_REPLAY")]
+    pub replay: Option<PathBuf>,
+
+    /// Start from a snapshot saved with F5, instead of the initial scene
+    #[clap(long, conflicts_with_all = ["record", "replay"], env = "GAFF_LOAD_SNAPSHOT")]
+    pub load_snapshot: Option<PathBuf>,
+
+    /// Simulated latency added to outgoing GGRS packets, e.g. 120ms
+    #[clap(long, default_value = "0ms", env = "GAFF_SIM_LATENCY")]
+    pub sim_latency: SimDuration,
+
+    /// Random variation of the simulated latency, e.g. 30ms
+    #[clap(long, default_value = "0ms", env = "GAFF_SIM_JITTER")]
+    pub sim_jitter: SimDuration,
+
+    /// Share of outgoing GGRS packets to drop, e.g. 5%
+    #[clap(long, default_value = "0%", env = "GAFF_SIM_LOSS")]
+    pub sim_loss: Percent,
+
+    /// Share of outgoing GGRS packets to send twice
+    #[clap(long, default_value = "0%", env = "GAFF_SIM_DUPLICATION")]
+    pub sim_duplication: Percent,
+
+    /// Share of outgoing GGRS packets to send after the next one
+    #[clap(long, default_value = "0%", env = "GAFF_SIM_REORDERING")]
+    pub sim_reordering: Percent,
+
+    /// Simulation frames per second
+    #[clap(long, default_value = "60", env = "GAFF_FPS")]
+    pub fps: usize,
+
+    /// Physics substeps per frame
+    #[clap(long, default_value = "6", env = "GAFF_SUBSTEPS")]
+    pub substeps: u32,
+
+    /// Downward acceleration, in pixels per second squared
+    #[clap(long, default_value = "1000", env = "GAFF_GRAVITY")]
+    pub gravity: Scalar,
+
+    /// How many frames GGRS may predict ahead of confirmed input
+    #[clap(long, default_value = "12", env = "GAFF_MAX_PREDICTION_WINDOW")]
+    pub max_prediction_window: usize,
+
+    /// Frames of delay added to local input, trading latency for rollbacks
+    #[clap(long, default_value = "0", env = "GAFF_INPUT_DELAY")]
+    pub input_delay: usize,
+
+    /// How far from the cursor, in pixels, bodies can be grabbed
+    #[clap(long, default_value = "100", env = "GAFF_GRAB_MAX_DISTANCE")]
+    pub grab_max_distance: Scalar,
+
+    /// Compliance of the joint pulling grabbed bodies, lower is stiffer
+    #[clap(long, default_value = "0.000001", env = "GAFF_GRAB_COMPLIANCE")]
+    pub grab_compliance: Scalar,
+
+    /// Linear velocity damping of grabbed bodies
+    #[clap(long, default_value = "5", env = "GAFF_GRAB_LINEAR_DAMPING")]
+    pub grab_linear_damping: Scalar,
+
+    /// Angular velocity damping of grabbed bodies
+    #[clap(long, default_value = "1", env = "GAFF_GRAB_ANGULAR_DAMPING")]
+    pub grab_angular_damping: Scalar,
+
+    /// TOML file with defaults for any of the above, see `DEFAULT_CONFIG`
+    #[clap(long, env = "GAFF_CONFIG")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    #[serde(skip)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Print the first entity and component that differs between two desync dumps
+    DiffDumps { a: PathBuf, b: PathBuf },
 }
 
 impl Default for Args {
@@ -29,29 +140,149 @@ impl Default for Args {
 }
 
 impl Args {
+    pub fn min_players(&self) -> usize {
+        self.min_players
+            .unwrap_or(self.players)
+            .clamp(1, self.players.max(1))
+    }
+
+    /// Reads the settings from all sources, see the module docs.
+    ///
+    /// Invalid settings are reported to the user, exiting on native.
     pub fn get() -> Self {
         #[cfg(target_arch = "wasm32")]
         {
-            let qs = web_sys::window()
-                .unwrap()
+            let window = web_sys::window().unwrap();
+            let qs = window
                 .location()
                 .search()
                 .unwrap()
                 .trim_start_matches('?')
                 .to_owned();
 
-            Args::from_query(&qs)
+            Args::from_query(&qs).unwrap_or_else(|e| {
+                let message = format!("invalid query string: {e}");
+                error!("{message}");
+                let _ = window.alert_with_message(&message);
+                Args::default()
+            })
         }
         #[cfg(not(target_arch = "wasm32"))]
         {
-            Args::parse()
+            let args = Args::parse();
+            let path = args.config.clone().or_else(|| {
+                let default = PathBuf::from(DEFAULT_CONFIG);
+                default.exists().then_some(default)
+            });
+            let Some(path) = path else {
+                return args;
+            };
+
+            std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|config| Args::parse_with_config(std::env::args_os(), &config))
+                .unwrap_or_else(|e| {
+                    eprintln!("error: invalid config file {path:?}: {e}");
+                    std::process::exit(2);
+                })
         }
     }
 
-    // #[allow(dead_code)]
+    /// Parses command line arguments and environment variables, falling back
+    /// to the given TOML config instead of the defaults.
+    pub fn parse_with_config<I, T>(args: I, config: &str) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = Args::command()
+            .try_get_matches_from(args)
+            .map_err(|e| e.to_string())?;
+        let mut args = Args::from_arg_matches(&matches).map_err(|e| e.to_string())?;
+        let config: ConfigFile = toml::from_str(config).map_err(|e| e.to_string())?;
+        config.apply(&mut args, &matches);
+        args.validate()?;
+        Ok(args)
+    }
+
+    /// Checks the constraints between arguments that clap checks on the
+    /// command line, settings from the config file bypass them
+    fn validate(&self) -> Result<(), String> {
+        if self.spectate && self.room.is_none() {
+            return Err("spectate requires a room".to_string());
+        }
+        if self.load_snapshot.is_some() {
+            let conflicts = [("record", &self.record), ("replay", &self.replay)];
+            if let Some((name, _)) = conflicts.iter().find(|(_, path)| path.is_some()) {
+                return Err(format!("load_snapshot can't be used with {name}"));
+            }
+        }
+        Ok(())
+    }
+
     #[cfg(target_arch = "wasm32")]
-    fn from_query(query: &str) -> Self {
-        // TODO: result?
-        serde_qs::from_str(query).unwrap()
+    pub fn from_query(query: &str) -> Result<Self, serde_qs::Error> {
+        serde_qs::from_str(query)
     }
 }
+
+/// Whether a setting was given on the command line or in the environment
+fn is_explicit(matches: &ArgMatches, id: &str) -> bool {
+    matches!(
+        matches.value_source(id),
+        Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+    )
+}
+
+macro_rules! config_file {
+    ($($field:ident: $ty:ty,)*) => {
+        /// The settings in a config file, any of them can be left out.
+        ///
+        /// Optional arguments are `Option<Option<_>>` here, so they can be
+        /// assigned as is.
+        #[derive(Deserialize, Default, Debug)]
+        #[serde(deny_unknown_fields)]
+        struct ConfigFile {
+            $($field: Option<$ty>,)*
+        }
+
+        impl ConfigFile {
+            fn apply(self, args: &mut Args, matches: &ArgMatches) {
+                $(
+                    if let Some(value) = self.$field {
+                        if !is_explicit(matches, stringify!($field)) {
+                            args.$field = value;
+                        }
+                    }
+                )*
+            }
+        }
+    };
+}
+
+config_file! {
+    matchbox: String,
+    room: Option<String>,
+    players: usize,
+    min_players: Option<usize>,
+    name: Option<String>,
+    spectate: bool,
+    headless: bool,
+    record: Option<PathBuf>,
+    replay: Option<PathBuf>,
+    load_snapshot: Option<PathBuf>,
+    sim_latency: SimDuration,
+    sim_jitter: SimDuration,
+    sim_loss: Percent,
+    sim_duplication: Percent,
+    sim_reordering: Percent,
+    fps: usize,
+    substeps: u32,
+    gravity: Scalar,
+    max_prediction_window: usize,
+    input_delay: usize,
+    grab_max_distance: Scalar,
+    grab_compliance: Scalar,
+    grab_linear_damping: Scalar,
+    grab_angular_damping: Scalar,
+}
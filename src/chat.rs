@@ -0,0 +1,271 @@
+//! Text chat between peers, in the lobby and in game
+//!
+//! Messages go over their own reliable matchbox channel, next to the GGRS and
+//! lobby channels, and never touch the rollback simulation. Both sending and
+//! receiving are rate limited, so a single peer can't flood everyone's chat.
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, Instant},
+};
+use bevy_inspector_egui::bevy_egui::{egui, EguiContexts, EguiPlugin};
+use bevy_matchbox::prelude::*;
+use std::{collections::VecDeque, time::Duration};
+
+use crate::lobby::{display_name, LobbyPeers, LocalPlayer, CHAT_CHANNEL};
+
+/// Longer messages are cut off
+const MAX_MESSAGE_LENGTH: usize = 200;
+/// How many messages to keep in the history
+const MAX_HISTORY: usize = 100;
+/// Each peer may send this many messages per [`RATE_LIMIT_WINDOW`]
+const RATE_LIMIT_MESSAGES: usize = 5;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(5);
+
+pub struct ChatPlugin;
+
+impl Plugin for ChatPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChatHistory>()
+            .init_resource::<ChatFocus>()
+            .init_resource::<ChatRateLimits>()
+            .add_event::<SendChat>()
+            .add_systems(
+                Update,
+                (send_chat, receive_chat)
+                    .run_if(resource_exists::<MatchboxSocket<MultipleChannels>>()),
+            );
+    }
+}
+
+/// The chat window, added by [`crate::graphics::GraphicsPlugin`]
+pub struct ChatUiPlugin;
+
+impl Plugin for ChatUiPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<EguiPlugin>() {
+            app.add_plugins(EguiPlugin);
+        }
+        app.init_resource::<ChatInput>().add_systems(
+            Update,
+            chat_ui
+                .before(send_chat)
+                .run_if(resource_exists::<MatchboxSocket<MultipleChannels>>()),
+        );
+    }
+}
+
+/// Sends a chat message to all connected peers
+#[derive(Event, Clone, Debug)]
+pub struct SendChat(pub String);
+
+#[derive(Clone, Debug)]
+pub struct ChatMessage {
+    /// `None` for notices from the chat itself
+    pub from: Option<String>,
+    pub text: String,
+}
+
+/// Recent chat messages, oldest first
+#[derive(Resource, Default, Debug)]
+pub struct ChatHistory(pub VecDeque<ChatMessage>);
+
+impl ChatHistory {
+    fn push(&mut self, from: Option<String>, text: String) {
+        if self.0.len() == MAX_HISTORY {
+            self.0.pop_front();
+        }
+        self.0.push_back(ChatMessage { from, text });
+    }
+}
+
+/// Whether the chat is being typed in, so keys don't also control the game
+#[derive(Resource, Default, Debug)]
+pub struct ChatFocus(pub bool);
+
+/// Run condition for systems that react to the keyboard
+pub fn chat_unfocused(focus: Res<ChatFocus>) -> bool {
+    !focus.0
+}
+
+/// When recent messages were sent, by sender, `None` for the local player
+#[derive(Resource, Default)]
+struct ChatRateLimits(HashMap<Option<PeerId>, VecDeque<Instant>>);
+
+impl ChatRateLimits {
+    /// Records a message, returns whether it's within the limit
+    fn allow(&mut self, sender: Option<PeerId>, now: Instant) -> bool {
+        let sent = self.0.entry(sender).or_default();
+        while sent
+            .front()
+            .is_some_and(|&time| now.duration_since(time) > RATE_LIMIT_WINDOW)
+        {
+            sent.pop_front();
+        }
+        if sent.len() >= RATE_LIMIT_MESSAGES {
+            return false;
+        }
+        sent.push_back(now);
+        true
+    }
+}
+
+fn sanitize_message(text: &str) -> String {
+    text.trim().chars().take(MAX_MESSAGE_LENGTH).collect()
+}
+
+fn send_chat(
+    mut events: EventReader<SendChat>,
+    mut socket: ResMut<MatchboxSocket<MultipleChannels>>,
+    mut history: ResMut<ChatHistory>,
+    mut rate_limits: ResMut<ChatRateLimits>,
+    local_player: Option<Res<LocalPlayer>>,
+) {
+    for SendChat(text) in events.iter() {
+        let text = sanitize_message(text);
+        if text.is_empty() {
+            continue;
+        }
+        let Some(local_id) = socket.id() else {
+            history.push(None, "Not connected yet".to_string());
+            continue;
+        };
+        if !rate_limits.allow(None, Instant::now()) {
+            history.push(None, "You're sending messages too quickly".to_string());
+            continue;
+        }
+
+        let peers: Vec<_> = socket.connected_peers().collect();
+        for peer in peers {
+            let packet = text.as_bytes().to_vec().into_boxed_slice();
+            socket.channel(CHAT_CHANNEL).send(packet, peer);
+        }
+        let name = local_player.as_ref().map_or("", |p| p.name.as_str());
+        history.push(Some(display_name(name, local_id)), text);
+    }
+}
+
+fn receive_chat(
+    mut socket: ResMut<MatchboxSocket<MultipleChannels>>,
+    mut history: ResMut<ChatHistory>,
+    mut rate_limits: ResMut<ChatRateLimits>,
+    peers: Option<Res<LobbyPeers>>,
+) {
+    let now = Instant::now();
+    for (peer, packet) in socket.channel(CHAT_CHANNEL).receive() {
+        if !rate_limits.allow(Some(peer), now) {
+            warn!("dropping chat message from {peer}, too many messages");
+            continue;
+        }
+        let text = sanitize_message(&String::from_utf8_lossy(&packet));
+        if text.is_empty() {
+            continue;
+        }
+        let name = peers
+            .as_ref()
+            .map_or_else(|| display_name("", peer), |peers| peers.name(peer));
+        info!("{name}: {text}");
+        history.push(Some(name), text);
+    }
+}
+
+/// The message being typed
+#[derive(Resource, Default)]
+struct ChatInput(String);
+
+fn chat_ui(
+    mut contexts: EguiContexts,
+    history: Res<ChatHistory>,
+    mut input: ResMut<ChatInput>,
+    mut focus: ResMut<ChatFocus>,
+    mut send: EventWriter<SendChat>,
+) {
+    egui::Window::new("Chat")
+        .anchor(egui::Align2::RIGHT_BOTTOM, [-10.0, -10.0])
+        .default_width(300.0)
+        .resizable(false)
+        .show(contexts.ctx_mut(), |ui| {
+            egui::ScrollArea::vertical()
+                .max_height(150.0)
+                .stick_to_bottom(true)
+                .show(ui, |ui| {
+                    for message in &history.0 {
+                        match &message.from {
+                            Some(from) => ui.label(format!("{from}: {}", message.text)),
+                            None => ui.weak(&message.text),
+                        };
+                    }
+                });
+
+            let response = ui.add(
+                egui::TextEdit::singleline(&mut input.0)
+                    .char_limit(MAX_MESSAGE_LENGTH)
+                    .hint_text("Say something"),
+            );
+            // singleline text edits lose focus when pressing enter
+            if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                send.send(SendChat(std::mem::take(&mut input.0)));
+                response.request_focus();
+            }
+            // also covers the frame enter was pressed in
+            focus.0 = response.has_focus() || response.lost_focus();
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn rate_limit_allows_a_burst_per_window() {
+        let mut limits = ChatRateLimits::default();
+        let start = Instant::now();
+        for _ in 0..RATE_LIMIT_MESSAGES {
+            assert!(limits.allow(None, start));
+        }
+        assert!(!limits.allow(None, start), "over the limit");
+        assert!(
+            !limits.allow(None, start + RATE_LIMIT_WINDOW),
+            "window not over yet"
+        );
+
+        // rejected messages don't count towards the limit
+        let later = start + RATE_LIMIT_WINDOW + Duration::from_millis(1);
+        for _ in 0..RATE_LIMIT_MESSAGES {
+            assert!(limits.allow(None, later));
+        }
+        assert!(!limits.allow(None, later));
+    }
+
+    #[test]
+    fn rate_limit_is_per_sender() {
+        let mut limits = ChatRateLimits::default();
+        let now = Instant::now();
+        let peer = Some(PeerId(Uuid::from_u128(1)));
+        for _ in 0..RATE_LIMIT_MESSAGES {
+            assert!(limits.allow(peer, now));
+        }
+        assert!(!limits.allow(peer, now));
+        assert!(
+            limits.allow(None, now),
+            "the local player has their own limit"
+        );
+    }
+
+    #[test]
+    fn rate_limit_window_slides() {
+        let mut limits = ChatRateLimits::default();
+        let start = Instant::now();
+        let step = RATE_LIMIT_WINDOW / RATE_LIMIT_MESSAGES as u32;
+        for i in 0..RATE_LIMIT_MESSAGES as u32 {
+            assert!(limits.allow(None, start + step * i));
+        }
+        let full = start + step * (RATE_LIMIT_MESSAGES as u32 - 1);
+        assert!(!limits.allow(None, full));
+        // only the first message has left the window
+        let first_expired = start + RATE_LIMIT_WINDOW + Duration::from_millis(1);
+        assert!(limits.allow(None, first_expired));
+        assert!(!limits.allow(None, first_expired));
+    }
+}
@@ -0,0 +1,239 @@
+//! Bit-exact checksums of physics components
+//!
+//! The xpbd components are not `Hash`, so they can't be used with
+//! [`bevy_ggrs::GgrsComponentChecksumHashPlugin`]. Instead, they implement
+//! [`BitHash`], which hashes the raw bits of their floats.
+//!
+//! Everything that's written to disk or compared between peers is hashed with
+//! [`Fnv1a`], which gives the same result on every platform.
+
+use bevy::prelude::*;
+use bevy_ggrs::{ChecksumFlag, ChecksumPart, Rollback, RollbackOrdered, SaveWorld, SaveWorldSet};
+use bevy_xpbd_2d::prelude::*;
+use std::{
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+};
+
+/// FNV-1a, unlike the std and bevy hashers it's the same on every platform.
+///
+/// Integers are hashed as little endian bytes, and `usize` as a `u64`, so
+/// 32-bit wasm peers agree with 64-bit native ones.
+#[derive(Clone, Copy, Debug)]
+pub struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_i16(&mut self, i: i16) {
+        self.write_u16(i as u16);
+    }
+
+    fn write_i32(&mut self, i: i32) {
+        self.write_u32(i as u32);
+    }
+
+    fn write_i64(&mut self, i: i64) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_i128(&mut self, i: i128) {
+        self.write_u128(i as u128);
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_i64(i as i64);
+    }
+}
+
+/// Bit-exact hashing, `-0.0` and `0.0` hash differently, as do different NaNs.
+///
+/// Entity ids differ between peers, so entities are hashed by their
+/// [`RollbackOrdered`] order, as returned by `ids`.
+pub trait BitHash {
+    fn bit_hash<H: Hasher>(&self, ids: &RollbackIds, state: &mut H);
+}
+
+/// Maps entities to their rollback order
+pub type RollbackIds<'a> = dyn Fn(Entity) -> u64 + 'a;
+
+impl BitHash for f32 {
+    fn bit_hash<H: Hasher>(&self, _ids: &RollbackIds, state: &mut H) {
+        self.to_bits().hash(state);
+    }
+}
+
+impl BitHash for Vec2 {
+    fn bit_hash<H: Hasher>(&self, ids: &RollbackIds, state: &mut H) {
+        self.x.bit_hash(ids, state);
+        self.y.bit_hash(ids, state);
+    }
+}
+
+impl BitHash for Vec3 {
+    fn bit_hash<H: Hasher>(&self, ids: &RollbackIds, state: &mut H) {
+        self.to_array().iter().for_each(|v| v.bit_hash(ids, state));
+    }
+}
+
+impl BitHash for Quat {
+    fn bit_hash<H: Hasher>(&self, ids: &RollbackIds, state: &mut H) {
+        self.to_array().iter().for_each(|v| v.bit_hash(ids, state));
+    }
+}
+
+impl BitHash for Entity {
+    fn bit_hash<H: Hasher>(&self, ids: &RollbackIds, state: &mut H) {
+        ids(*self).hash(state);
+    }
+}
+
+impl BitHash for Transform {
+    fn bit_hash<H: Hasher>(&self, ids: &RollbackIds, state: &mut H) {
+        self.translation.bit_hash(ids, state);
+        self.rotation.bit_hash(ids, state);
+        self.scale.bit_hash(ids, state);
+    }
+}
+
+impl BitHash for Position {
+    fn bit_hash<H: Hasher>(&self, ids: &RollbackIds, state: &mut H) {
+        self.0.bit_hash(ids, state);
+    }
+}
+
+impl BitHash for PreviousPosition {
+    fn bit_hash<H: Hasher>(&self, ids: &RollbackIds, state: &mut H) {
+        self.0.bit_hash(ids, state);
+    }
+}
+
+impl BitHash for Rotation {
+    fn bit_hash<H: Hasher>(&self, ids: &RollbackIds, state: &mut H) {
+        self.cos().bit_hash(ids, state);
+        self.sin().bit_hash(ids, state);
+    }
+}
+
+impl BitHash for PreviousRotation {
+    fn bit_hash<H: Hasher>(&self, ids: &RollbackIds, state: &mut H) {
+        self.0.bit_hash(ids, state);
+    }
+}
+
+impl BitHash for LinearVelocity {
+    fn bit_hash<H: Hasher>(&self, ids: &RollbackIds, state: &mut H) {
+        self.0.bit_hash(ids, state);
+    }
+}
+
+impl BitHash for AngularVelocity {
+    fn bit_hash<H: Hasher>(&self, ids: &RollbackIds, state: &mut H) {
+        self.0.bit_hash(ids, state);
+    }
+}
+
+impl BitHash for DistanceJoint {
+    fn bit_hash<H: Hasher>(&self, ids: &RollbackIds, state: &mut H) {
+        self.entity1.bit_hash(ids, state);
+        self.entity2.bit_hash(ids, state);
+        self.local_anchor1.bit_hash(ids, state);
+        self.local_anchor2.bit_hash(ids, state);
+        self.rest_length.bit_hash(ids, state);
+        self.damping_linear.bit_hash(ids, state);
+        self.damping_angular.bit_hash(ids, state);
+        self.lagrange.bit_hash(ids, state);
+        self.compliance.bit_hash(ids, state);
+        self.force.bit_hash(ids, state);
+    }
+}
+
+/// Adds the [`BitHash`] of every rollback entity's `C` to the GGRS checksum.
+pub struct GgrsComponentChecksumBitHashPlugin<C>(PhantomData<C>);
+
+impl<C> Default for GgrsComponentChecksumBitHashPlugin<C> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<C> GgrsComponentChecksumBitHashPlugin<C>
+where
+    C: Component + BitHash,
+{
+    fn update(
+        mut commands: Commands,
+        order: Res<RollbackOrdered>,
+        rollbacks: Query<&Rollback>,
+        components: Query<(&Rollback, &C)>,
+        mut checksum: Query<&mut ChecksumPart, (Without<Rollback>, With<ChecksumFlag<C>>)>,
+    ) {
+        let ids = |entity| {
+            rollbacks
+                .get(entity)
+                .map_or(u64::MAX, |rollback| order.order(*rollback))
+        };
+        let mut result = 0;
+
+        for (rollback, component) in &components {
+            let mut hasher = Fnv1a::default();
+            // the rollback order is the same on all peers, unlike entity ids
+            order.order(*rollback).hash(&mut hasher);
+            component.bit_hash(&ids, &mut hasher);
+            // xor, so the result does not depend on query iteration order
+            result ^= hasher.finish();
+        }
+
+        let result = ChecksumPart(result as u128);
+
+        if let Ok(mut checksum) = checksum.get_single_mut() {
+            *checksum = result;
+        } else {
+            commands.spawn((result, ChecksumFlag::<C>::default()));
+        }
+    }
+}
+
+impl<C> Plugin for GgrsComponentChecksumBitHashPlugin<C>
+where
+    C: Component + BitHash,
+{
+    fn build(&self, app: &mut App) {
+        app.add_systems(SaveWorld, Self::update.in_set(SaveWorldSet::Checksum));
+    }
+}
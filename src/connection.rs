@@ -0,0 +1,154 @@
+//! Peers losing their connection during a match
+//!
+//! GGRS reports when a peer stops responding, and disconnects it if it stays
+//! silent for too long. The remaining players see a countdown while the
+//! connection is interrupted, and when a peer has left, they can either keep
+//! playing or return to the lobby.
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, Instant},
+};
+use bevy_ggrs::ggrs::GgrsEvent;
+use bevy_matchbox::prelude::PeerId;
+use std::time::Duration;
+
+use crate::{AppState, GgrsConfig};
+
+pub struct ConnectionPlugin;
+
+impl Plugin for ConnectionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PeerConnections>().add_systems(
+            Update,
+            (
+                update_connection_overlay,
+                connection_keyboard
+                    .run_if(crate::in_match)
+                    .run_if(crate::chat::chat_unfocused),
+            ),
+        );
+    }
+}
+
+/// The state of remote peers that are not connected as they should be
+#[derive(Resource, Default, Debug)]
+pub struct PeerConnections {
+    /// Peers we haven't heard from lately, with the time GGRS gives up on them
+    pub interrupted: HashMap<PeerId, Instant>,
+    /// Peers that left the match, until the remaining players decide what to do
+    pub disconnected: Vec<PeerId>,
+}
+
+impl PeerConnections {
+    pub(crate) fn handle_event(&mut self, event: &GgrsEvent<GgrsConfig>) {
+        match *event {
+            GgrsEvent::NetworkInterrupted {
+                addr,
+                disconnect_timeout,
+            } => {
+                warn!("connection to {addr} interrupted");
+                let timeout = Duration::from_millis(disconnect_timeout as u64);
+                self.interrupted.insert(addr, Instant::now() + timeout);
+            }
+            GgrsEvent::NetworkResumed { addr } => {
+                info!("connection to {addr} resumed");
+                self.interrupted.remove(&addr);
+            }
+            GgrsEvent::Disconnected { addr } => {
+                warn!("{addr} disconnected");
+                self.interrupted.remove(&addr);
+                self.disconnected.push(addr);
+            }
+            _ => {}
+        }
+    }
+
+    fn message(&self, now: Instant) -> Option<String> {
+        if self.interrupted.is_empty() && self.disconnected.is_empty() {
+            return None;
+        }
+
+        let mut lines = Vec::new();
+        for (peer, deadline) in &self.interrupted {
+            let remaining = deadline.saturating_duration_since(now).as_secs_f32().ceil();
+            lines.push(format!(
+                "Connection to {peer} interrupted, disconnecting in {remaining}s"
+            ));
+        }
+        for peer in &self.disconnected {
+            lines.push(format!("{peer} left the match"));
+        }
+        if !self.disconnected.is_empty() {
+            lines.push("Enter: keep playing, Escape: back to lobby".to_string());
+        }
+        Some(lines.join("\n"))
+    }
+}
+
+#[derive(Component)]
+struct ConnectionOverlay;
+
+fn update_connection_overlay(
+    mut commands: Commands,
+    connections: Res<PeerConnections>,
+    asset_server: Option<Res<AssetServer>>,
+    mut overlays: Query<(Entity, &mut Text), With<ConnectionOverlay>>,
+) {
+    // No UI when running headless
+    let Some(asset_server) = asset_server else {
+        return;
+    };
+
+    let message = connections.message(Instant::now());
+
+    match (message, overlays.get_single_mut()) {
+        (Some(message), Ok((_, mut text))) => {
+            text.sections[0].value = message;
+        }
+        (Some(message), Err(_)) => {
+            commands.spawn((
+                TextBundle::from_section(
+                    message,
+                    TextStyle {
+                        font: asset_server.load("fonts/quicksand-light.ttf"),
+                        font_size: 36.,
+                        color: Color::ORANGE,
+                    },
+                )
+                .with_style(Style {
+                    position_type: PositionType::Absolute,
+                    bottom: Val::Px(10.0),
+                    left: Val::Px(10.0),
+                    ..default()
+                }),
+                ConnectionOverlay,
+            ));
+        }
+        (None, Ok((entity, _))) => {
+            commands.entity(entity).despawn_recursive();
+        }
+        (None, Err(_)) => {}
+    }
+}
+
+fn connection_keyboard(
+    keyboard: Option<Res<Input<KeyCode>>>,
+    mut connections: ResMut<PeerConnections>,
+    mut app_state: ResMut<NextState<AppState>>,
+) {
+    // Without a keyboard, e.g. when headless, the remaining players keep playing
+    let Some(keyboard) = keyboard else {
+        return;
+    };
+    if connections.disconnected.is_empty() {
+        return;
+    }
+
+    if keyboard.just_pressed(KeyCode::Return) {
+        connections.disconnected.clear();
+    } else if keyboard.just_pressed(KeyCode::Escape) {
+        info!("returning to lobby");
+        app_state.set(AppState::Lobby);
+    }
+}
@@ -0,0 +1,734 @@
+//! Desync debugging
+//!
+//! The rollback state of recent frames is kept around, so when GGRS detects a
+//! desync, each peer can write a dump of the affected frame to disk. Dumps
+//! from two peers can then be compared with `bevy_gaff diff-dumps a.txt b.txt`.
+//!
+//! In SyncTest sessions, every resimulated frame is also compared with the
+//! original run, and mismatches are reported per component type and entity.
+
+use bevy::{prelude::*, utils::HashMap};
+use bevy_ggrs::{Rollback, RollbackOrdered, Session};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fmt::Write,
+    hash::{Hash, Hasher},
+};
+
+use crate::{
+    checksum::{BitHash, Fnv1a, RollbackIds},
+    FrameCount, GgrsConfig,
+};
+
+/// How many frames of dumps to keep, needs to cover the prediction window and
+/// the desync detection interval.
+const DUMP_HISTORY: usize = 64;
+
+pub struct DesyncPlugin;
+
+impl Plugin for DesyncPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<FrameDumps>()
+            .init_resource::<SyncTestMismatches>()
+            .init_resource::<DumpRegistry>()
+            .add_systems(
+                Update,
+                show_desync_error
+                    .run_if(resource_added::<Desynced>())
+                    .run_if(crate::in_match),
+            );
+    }
+}
+
+/// Inserted when a desync has been detected
+#[derive(Resource, Debug, Clone)]
+pub struct Desynced {
+    pub frame: usize,
+    pub dump_path: Option<String>,
+}
+
+/// The raw bits of the snapshotted components of a single rollback entity
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntityDump {
+    pub rollback_id: u64,
+    pub components: Vec<(&'static str, Vec<u64>)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameDump {
+    pub frame: usize,
+    pub resources: Vec<(&'static str, Vec<u64>)>,
+    pub entities: Vec<EntityDump>,
+}
+
+impl FrameDump {
+    pub fn to_text(&self) -> String {
+        let mut text = format!("frame {}\n", self.frame);
+        for (name, bits) in &self.resources {
+            write!(text, "resource {name}").unwrap();
+            for value in bits {
+                write!(text, " {value:08x}").unwrap();
+            }
+            text.push('\n');
+        }
+        for entity in &self.entities {
+            writeln!(text, "entity {}", entity.rollback_id).unwrap();
+            for (name, bits) in &entity.components {
+                write!(text, "{name}").unwrap();
+                for value in bits {
+                    write!(text, " {value:08x}").unwrap();
+                }
+                text.push('\n');
+            }
+        }
+        text
+    }
+
+    /// The bits of each component type, by rollback id
+    fn components_by_type(&self) -> BTreeMap<&'static str, BTreeMap<u64, &[u64]>> {
+        let mut by_type: BTreeMap<_, BTreeMap<_, _>> = BTreeMap::new();
+        for (name, bits) in &self.resources {
+            by_type
+                .entry(*name)
+                .or_default()
+                .insert(u64::MAX, &bits[..]);
+        }
+        for entity in &self.entities {
+            for (name, bits) in &entity.components {
+                by_type
+                    .entry(*name)
+                    .or_default()
+                    .insert(entity.rollback_id, &bits[..]);
+            }
+        }
+        by_type
+    }
+
+    /// A checksum of the whole frame, the same on every platform
+    pub fn checksum(&self) -> u64 {
+        let mut hasher = Fnv1a::default();
+        self.type_checksums().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// A separate checksum for each rollback-registered component and resource
+    pub fn type_checksums(&self) -> BTreeMap<&'static str, u64> {
+        self.components_by_type()
+            .into_iter()
+            .map(|(name, components)| {
+                let mut hasher = Fnv1a::default();
+                // slices of integers hash their native endian bytes
+                for (rollback_id, bits) in components {
+                    hasher.write_u64(rollback_id);
+                    hasher.write_usize(bits.len());
+                    bits.iter().for_each(|bits| hasher.write_u64(*bits));
+                }
+                (name, hasher.finish())
+            })
+            .collect()
+    }
+}
+
+/// A component type or resource that differs between two runs of a frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeMismatch {
+    pub type_name: &'static str,
+    pub original_checksum: Option<u64>,
+    pub resimulated_checksum: Option<u64>,
+    /// The rollback entities that differ, empty for resources
+    pub rollback_ids: Vec<u64>,
+}
+
+impl std::fmt::Display for TypeMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let checksum = |c: Option<u64>| c.map_or("<missing>".to_string(), |c| format!("{c:016x}"));
+        write!(
+            f,
+            "{}: {} -> {}",
+            self.type_name,
+            checksum(self.original_checksum),
+            checksum(self.resimulated_checksum)
+        )?;
+        if !self.rollback_ids.is_empty() {
+            write!(f, ", entities {:?}", self.rollback_ids)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares the types and entities of two runs of the same frame
+pub fn compare_frame_dumps(original: &FrameDump, resimulated: &FrameDump) -> Vec<TypeMismatch> {
+    let original_checksums = original.type_checksums();
+    let resimulated_checksums = resimulated.type_checksums();
+    let original_by_type = original.components_by_type();
+    let resimulated_by_type = resimulated.components_by_type();
+
+    let type_names: BTreeSet<_> = original_checksums
+        .keys()
+        .chain(resimulated_checksums.keys())
+        .copied()
+        .collect();
+
+    let mut mismatches = Vec::new();
+    for type_name in type_names {
+        let original_checksum = original_checksums.get(type_name).copied();
+        let resimulated_checksum = resimulated_checksums.get(type_name).copied();
+        if original_checksum == resimulated_checksum {
+            continue;
+        }
+
+        let empty = BTreeMap::new();
+        let original_components = original_by_type.get(type_name).unwrap_or(&empty);
+        let resimulated_components = resimulated_by_type.get(type_name).unwrap_or(&empty);
+        let rollback_ids = original_components
+            .keys()
+            .chain(resimulated_components.keys())
+            .copied()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .filter(|id| *id != u64::MAX)
+            .filter(|id| original_components.get(id) != resimulated_components.get(id))
+            .collect();
+
+        mismatches.push(TypeMismatch {
+            type_name,
+            original_checksum,
+            resimulated_checksum,
+            rollback_ids,
+        });
+    }
+    mismatches
+}
+
+/// A frame that resimulated differently in a SyncTest session
+#[derive(Debug, Clone)]
+pub struct ResimulationMismatch {
+    pub frame: usize,
+    pub types: Vec<TypeMismatch>,
+}
+
+/// All resimulation mismatches detected in SyncTest sessions
+#[derive(Resource, Default, Debug)]
+pub struct SyncTestMismatches(pub Vec<ResimulationMismatch>);
+
+/// Dumps of the most recent frames
+#[derive(Resource, Default)]
+pub struct FrameDumps(VecDeque<FrameDump>);
+
+impl FrameDumps {
+    pub fn get(&self, frame: usize) -> Option<&FrameDump> {
+        self.0.iter().find(|dump| dump.frame == frame)
+    }
+
+    /// Forgets dumps after `frame`, when the world is restored to an earlier state
+    pub(crate) fn truncate(&mut self, frame: usize) {
+        self.0.retain(|dump| dump.frame <= frame);
+    }
+
+    /// Returns the replaced dump if the frame was simulated before
+    fn insert(&mut self, dump: FrameDump) -> Option<FrameDump> {
+        // after a rollback, resimulated frames replace the mispredicted ones
+        if let Some(existing) = self.0.iter_mut().find(|d| d.frame == dump.frame) {
+            return Some(std::mem::replace(existing, dump));
+        }
+        if self.0.len() == DUMP_HISTORY {
+            self.0.pop_front();
+        }
+        self.0.push_back(dump);
+        None
+    }
+}
+
+/// Collects the values a [`BitHash`] impl hashes, instead of hashing them
+#[derive(Default)]
+struct BitCollector(Vec<u64>);
+
+impl Hasher for BitCollector {
+    /// The [`Fnv1a`] hash of the collected values
+    fn finish(&self) -> u64 {
+        let mut hasher = Fnv1a::default();
+        self.0.iter().for_each(|value| hasher.write_u64(*value));
+        hasher.finish()
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.extend(bytes.iter().map(|byte| *byte as u64));
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.0.push(i as u64);
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.0.push(i as u64);
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.0.push(i as u64);
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.0.push(i);
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.0.push(i as u64);
+    }
+}
+
+fn bits<T: BitHash>(value: &T, ids: &RollbackIds) -> Vec<u64> {
+    let mut collector = BitCollector::default();
+    value.bit_hash(ids, &mut collector);
+    collector.0
+}
+
+/// The type name without its module path
+fn short_type_name<T>() -> &'static str {
+    let name = std::any::type_name::<T>();
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+type ComponentDumpFn = fn(&mut World, &RollbackIds, &mut BTreeMap<u64, EntityDump>);
+type ResourceDumpFn = fn(&World) -> Option<(&'static str, Vec<u64>)>;
+
+/// How to dump the types registered with [`crate::rollback::RollbackApp`]
+#[derive(Resource, Default)]
+pub(crate) struct DumpRegistry {
+    components: Vec<ComponentDumpFn>,
+    resources: Vec<ResourceDumpFn>,
+}
+
+impl DumpRegistry {
+    pub(crate) fn add_component<C: Component + BitHash>(&mut self) {
+        self.components.push(|world, ids, entities| {
+            let mut query = world.query_filtered::<(Entity, &C), With<Rollback>>();
+            for (entity, component) in query.iter(world) {
+                if let Some(dump) = entities.get_mut(&ids(entity)) {
+                    let component = (short_type_name::<C>(), bits(component, ids));
+                    dump.components.push(component);
+                }
+            }
+        });
+    }
+
+    pub(crate) fn add_resource<R: Resource + BitHash>(&mut self) {
+        self.resources.push(|world| {
+            let resource = world.get_resource::<R>()?;
+            Some((short_type_name::<R>(), bits(resource, &|_| u64::MAX)))
+        });
+    }
+}
+
+/// Records the rollback state of the current frame, runs at the end of each
+/// frame in the rollback schedule.
+pub fn record_frame_dump(world: &mut World) {
+    let mut rollbacks = world.query::<(Entity, &Rollback)>();
+    let order = world.resource::<RollbackOrdered>();
+    let rollback_ids: HashMap<Entity, u64> = rollbacks
+        .iter(world)
+        .map(|(entity, rollback)| (entity, order.order(*rollback)))
+        .collect();
+    let ids = |entity| rollback_ids.get(&entity).copied().unwrap_or(u64::MAX);
+
+    let mut entities: BTreeMap<_, _> = rollback_ids
+        .values()
+        .map(|&rollback_id| {
+            let dump = EntityDump {
+                rollback_id,
+                components: Vec::new(),
+            };
+            (rollback_id, dump)
+        })
+        .collect();
+    let resources: Vec<_> = world.resource_scope(|world, registry: Mut<DumpRegistry>| {
+        for dump_components in &registry.components {
+            dump_components(world, &ids, &mut entities);
+        }
+        registry
+            .resources
+            .iter()
+            .filter_map(|dump_resource| dump_resource(world))
+            .collect()
+    });
+
+    let dump = FrameDump {
+        frame: world.resource::<FrameCount>().frame,
+        resources,
+        entities: entities.into_values().collect(),
+    };
+
+    let original = world.resource_mut::<FrameDumps>().insert(dump.clone());
+
+    // in SyncTest sessions, resimulated frames have the same input, so they
+    // should be identical to the original run
+    let is_synctest = matches!(
+        world.get_resource::<Session<GgrsConfig>>(),
+        Some(Session::SyncTest(_))
+    );
+    let Some(original) = original.filter(|_| is_synctest) else {
+        return;
+    };
+
+    let types = compare_frame_dumps(&original, &dump);
+    if !types.is_empty() {
+        let report: Vec<_> = types.iter().map(ToString::to_string).collect();
+        error!(
+            "frame {} resimulated differently:\n  {}",
+            dump.frame,
+            report.join("\n  ")
+        );
+        world
+            .resource_mut::<SyncTestMismatches>()
+            .0
+            .push(ResimulationMismatch {
+                frame: dump.frame,
+                types,
+            });
+    }
+}
+
+/// Writes the dump of the given frame, returns where it was written.
+///
+/// On wasm there's no file system, the dump is logged to the console and
+/// `None` is returned.
+pub fn write_frame_dump(dumps: &FrameDumps, frame: usize, player: usize) -> Option<String> {
+    let Some(dump) = dumps.get(frame) else {
+        error!("no dump of frame {frame}, it is too old");
+        return None;
+    };
+
+    let path = format!("desync-frame{frame}-player{player}.txt");
+
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        match std::fs::write(&path, dump.to_text()) {
+            Ok(()) => {
+                error!("wrote desync dump to {path}");
+                Some(path)
+            }
+            Err(e) => {
+                error!("failed to write desync dump to {path}: {e}");
+                None
+            }
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    {
+        // no file system, dump to the console instead
+        error!("desync dump {path}:\n{}", dump.to_text());
+        None
+    }
+}
+
+/// The first place two dumps differ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Divergence {
+    Frame {
+        a: String,
+        b: String,
+    },
+    Resource {
+        resource: String,
+        a: Option<String>,
+        b: Option<String>,
+    },
+    MissingEntity {
+        rollback_id: String,
+        in_a: bool,
+    },
+    Component {
+        rollback_id: String,
+        component: String,
+        a: Option<String>,
+        b: Option<String>,
+    },
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Divergence::Frame { a, b } => write!(f, "dumps are of different frames: {a} vs {b}"),
+            Divergence::Resource { resource, a, b } => {
+                let a = a.as_deref().unwrap_or("<missing>");
+                let b = b.as_deref().unwrap_or("<missing>");
+                write!(f, "resource {resource} differs:\n  a: {a}\n  b: {b}")
+            }
+            Divergence::MissingEntity { rollback_id, in_a } => {
+                let missing_in = if *in_a { "b" } else { "a" };
+                write!(f, "entity {rollback_id} is missing in {missing_in}")
+            }
+            Divergence::Component {
+                rollback_id,
+                component,
+                a,
+                b,
+            } => {
+                let a = a.as_deref().unwrap_or("<missing>");
+                let b = b.as_deref().unwrap_or("<missing>");
+                write!(
+                    f,
+                    "entity {rollback_id} {component} differs:\n  a: {a}\n  b: {b}"
+                )
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct ParsedDump<'a> {
+    frame: Option<&'a str>,
+    resources: Vec<(&'a str, &'a str)>,
+    entities: Vec<(&'a str, Vec<(&'a str, &'a str)>)>,
+}
+
+fn parse_dump(text: &str) -> ParsedDump<'_> {
+    let mut dump = ParsedDump::default();
+    for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+        match key {
+            "frame" => dump.frame = Some(value),
+            "resource" => dump
+                .resources
+                .push(value.split_once(' ').unwrap_or((value, ""))),
+            "entity" => dump.entities.push((value, Vec::new())),
+            _ => {
+                if let Some((_, components)) = dump.entities.last_mut() {
+                    components.push((key, value));
+                }
+            }
+        }
+    }
+    dump
+}
+
+fn find_value(values: &[(&str, &str)], name: &str) -> Option<String> {
+    values
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, v)| v.to_string())
+}
+
+/// Finds the first entity and component that differs between two dumps
+pub fn diff_dumps(a: &str, b: &str) -> Option<Divergence> {
+    let a = parse_dump(a);
+    let b = parse_dump(b);
+
+    if a.frame != b.frame {
+        return Some(Divergence::Frame {
+            a: a.frame.unwrap_or_default().to_string(),
+            b: b.frame.unwrap_or_default().to_string(),
+        });
+    }
+
+    for (name, _) in a.resources.iter().chain(b.resources.iter()) {
+        let (value_a, value_b) = (
+            find_value(&a.resources, name),
+            find_value(&b.resources, name),
+        );
+        if value_a != value_b {
+            return Some(Divergence::Resource {
+                resource: name.to_string(),
+                a: value_a,
+                b: value_b,
+            });
+        }
+    }
+
+    for (id, components_a) in &a.entities {
+        let Some((_, components_b)) = b.entities.iter().find(|(id_b, _)| id_b == id) else {
+            return Some(Divergence::MissingEntity {
+                rollback_id: id.to_string(),
+                in_a: true,
+            });
+        };
+
+        for (name, _) in components_a.iter().chain(components_b.iter()) {
+            let (value_a, value_b) = (
+                find_value(components_a, name),
+                find_value(components_b, name),
+            );
+            if value_a != value_b {
+                return Some(Divergence::Component {
+                    rollback_id: id.to_string(),
+                    component: name.to_string(),
+                    a: value_a,
+                    b: value_b,
+                });
+            }
+        }
+    }
+
+    b.entities
+        .iter()
+        .find(|(id, _)| !a.entities.iter().any(|(id_a, _)| id_a == id))
+        .map(|(id, _)| Divergence::MissingEntity {
+            rollback_id: id.to_string(),
+            in_a: false,
+        })
+}
+
+fn show_desync_error(
+    mut commands: Commands,
+    desynced: Res<Desynced>,
+    asset_server: Option<Res<AssetServer>>,
+) {
+    // No UI when running headless
+    let Some(asset_server) = asset_server else {
+        return;
+    };
+
+    let mut message = format!("Desynced on frame {}", desynced.frame);
+    if let Some(path) = &desynced.dump_path {
+        message += &format!("\ndump written to {path}");
+    }
+
+    commands.spawn(
+        TextBundle::from_section(
+            message,
+            TextStyle {
+                font: asset_server.load("fonts/quicksand-light.ttf"),
+                font_size: 48.,
+                color: Color::RED,
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(10.0),
+            left: Val::Px(10.0),
+            ..default()
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bit_collector_hashes_the_collected_values() {
+        let collect = |values: &[u32]| {
+            let mut collector = BitCollector::default();
+            values.iter().for_each(|value| collector.write_u32(*value));
+            collector
+        };
+        assert_eq!(collect(&[1, 2]).0, [1, 2]);
+        assert_eq!(collect(&[1, 2]).finish(), collect(&[1, 2]).finish());
+        assert_ne!(collect(&[1, 2]).finish(), collect(&[2, 1]).finish());
+    }
+
+    fn dump(position: u64, joint: u64) -> FrameDump {
+        FrameDump {
+            frame: 10,
+            resources: vec![("FrameCount", vec![10]), ("PauseState", vec![0, 0])],
+            entities: vec![
+                EntityDump {
+                    rollback_id: 0,
+                    components: vec![("Position", vec![position, 2])],
+                },
+                EntityDump {
+                    rollback_id: 1,
+                    components: vec![("Position", vec![3, 4]), ("DistanceJoint", vec![0, joint])],
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn identical_dumps_match() {
+        assert!(compare_frame_dumps(&dump(1, 5), &dump(1, 5)).is_empty());
+        assert_eq!(dump(1, 5).checksum(), dump(1, 5).checksum());
+    }
+
+    #[test]
+    fn mismatches_are_reported_per_type_and_entity() {
+        let mismatches = compare_frame_dumps(&dump(1, 5), &dump(9, 5));
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].type_name, "Position");
+        assert_eq!(mismatches[0].rollback_ids, [0]);
+
+        let mismatches = compare_frame_dumps(&dump(1, 5), &dump(9, 6));
+        let types: Vec<_> = mismatches.iter().map(|m| m.type_name).collect();
+        assert_eq!(types, ["DistanceJoint", "Position"]);
+    }
+
+    #[test]
+    fn missing_types_and_resources_are_reported() {
+        let original = dump(1, 5);
+        let mut resimulated = original.clone();
+        resimulated.resources[1].1 = vec![1, 2];
+        resimulated.entities[1].components.pop();
+
+        let mismatches = compare_frame_dumps(&original, &resimulated);
+        assert_eq!(mismatches.len(), 2);
+        assert_eq!(mismatches[0].type_name, "DistanceJoint");
+        assert_eq!(mismatches[0].resimulated_checksum, None);
+        assert_eq!(mismatches[0].rollback_ids, [1]);
+        // resources are not entities
+        assert_eq!(mismatches[1].type_name, "PauseState");
+        assert!(mismatches[1].rollback_ids.is_empty());
+    }
+
+    #[test]
+    fn diff_of_identical_dumps_is_none() {
+        let text = dump(1, 5).to_text();
+        assert_eq!(diff_dumps(&text, &text), None);
+    }
+
+    #[test]
+    fn diff_finds_first_divergence() {
+        let a = dump(1, 5);
+
+        let mut b = a.clone();
+        b.frame = 11;
+        assert_eq!(
+            diff_dumps(&a.to_text(), &b.to_text()),
+            Some(Divergence::Frame {
+                a: "10".to_string(),
+                b: "11".to_string()
+            })
+        );
+
+        let mut b = a.clone();
+        b.resources[1].1 = vec![1, 1];
+        assert_eq!(
+            diff_dumps(&a.to_text(), &b.to_text()),
+            Some(Divergence::Resource {
+                resource: "PauseState".to_string(),
+                a: Some("00000000 00000000".to_string()),
+                b: Some("00000001 00000001".to_string()),
+            })
+        );
+
+        assert_eq!(
+            diff_dumps(&a.to_text(), &dump(1, 6).to_text()),
+            Some(Divergence::Component {
+                rollback_id: "1".to_string(),
+                component: "DistanceJoint".to_string(),
+                a: Some("00000000 00000005".to_string()),
+                b: Some("00000000 00000006".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn diff_finds_missing_entities() {
+        let a = dump(1, 5);
+        let mut b = a.clone();
+        b.entities.pop();
+        assert_eq!(
+            diff_dumps(&a.to_text(), &b.to_text()),
+            Some(Divergence::MissingEntity {
+                rollback_id: "1".to_string(),
+                in_a: true
+            })
+        );
+        assert_eq!(
+            diff_dumps(&b.to_text(), &a.to_text()),
+            Some(Divergence::MissingEntity {
+                rollback_id: "1".to_string(),
+                in_a: false
+            })
+        );
+    }
+}
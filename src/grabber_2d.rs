@@ -1,55 +1,191 @@
 //! 2D grabber plugin for bevy_xpbd_2d
+//!
+//! Players drag bodies around with a [`DistanceJoint`] between the body and a
+//! kinematic [`Grabber`] at their cursor. Everything is rolled back, so adding
+//! [`GrabberPlugin`] is enough to get networked grabbing.
 
 use bevy::prelude::*;
-use bevy_ggrs::{AddRollbackCommandExtension, PlayerInputs};
+use bevy_ggrs::{
+    ggrs::{Config, InputStatus},
+    AddRollbackCommandExtension, GgrsComponentChecksumHashPlugin, GgrsPlugin, GgrsSchedule,
+    PlayerInputs,
+};
 use bevy_xpbd_2d::{math::*, prelude::*};
+use serde::{Deserialize, Serialize};
+use std::{
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+};
 
-use crate::{input::INPUT_MOUSE_LEFT, GgrsConfig};
+use crate::{
+    checksum::{BitHash, GgrsComponentChecksumBitHashPlugin, RollbackIds},
+    rollback::RollbackApp,
+};
 
-#[derive(Default)]
-pub struct GrabberPlugin;
+/// Adds grabbing for the players of GGRS sessions configured with `C`.
+///
+/// Needs to be added after [`GgrsPlugin`], which sets up the rollback
+/// schedule. [`GrabberSet`] has to be ordered before the physics step in the
+/// rollback schedule, [`crate::GaffPlugin`] orders it before
+/// [`crate::PhysicsStepSet`].
+pub struct GrabberPlugin<C> {
+    /// The settings matches start with
+    pub settings: GrabberSettings,
+    _config: PhantomData<C>,
+}
+
+impl<C> GrabberPlugin<C> {
+    pub fn new(settings: GrabberSettings) -> Self {
+        Self {
+            settings,
+            _config: PhantomData,
+        }
+    }
+}
+
+impl<C> Default for GrabberPlugin<C> {
+    fn default() -> Self {
+        Self::new(default())
+    }
+}
+
+impl<C> Plugin for GrabberPlugin<C>
+where
+    C: Config,
+    C::Input: GrabberInput,
+{
+    fn build(&self, app: &mut App) {
+        assert!(
+            app.is_plugin_added::<GgrsPlugin<C>>(),
+            "GrabberPlugin needs to be added after GgrsPlugin"
+        );
+
+        app.insert_resource(self.settings)
+            .insert_resource(InitialGrabberSettings(self.settings))
+            .rollback_resource::<GrabberSettings>()
+            .rollback_component::<Grabber>()
+            .rollback_component::<GrabberJoint>()
+            .rollback_component_with_map_entities::<DistanceJoint>(|joint, map| {
+                joint.entity1 = map(joint.entity1);
+                joint.entity2 = map(joint.entity2);
+            })
+            .add_plugins(GgrsComponentChecksumHashPlugin::<Grabber>::default())
+            .add_plugins(GgrsComponentChecksumHashPlugin::<GrabberJoint>::default())
+            .add_plugins(GgrsComponentChecksumBitHashPlugin::<DistanceJoint>::default())
+            .add_systems(GgrsSchedule, grab::<C>.in_set(GrabberSet));
+    }
+}
+
+/// The set [`grab`] runs in, in the rollback schedule
+#[derive(SystemSet, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct GrabberSet;
+
+/// Input that controls a player's grabber
+pub trait GrabberInput {
+    /// Whether the grab button is held
+    fn grabbing(&self) -> bool;
+    /// Where the grabber is, in world coordinates
+    fn grab_position(&self) -> Vector;
+}
+
+/// How grabbers pick up and pull bodies.
+///
+/// This is rolled back with the simulation, so it can be changed during a
+/// match, as long as it's changed in the rollback schedule.
+#[derive(Resource, Clone, Copy, Debug, PartialEq, Reflect, Serialize, Deserialize)]
+#[reflect(Resource)]
+pub struct GrabberSettings {
+    /// How far from the cursor a body can be grabbed
+    pub max_distance: Scalar,
+    /// Compliance of the joint pulling the body to the cursor
+    pub compliance: Scalar,
+    pub linear_damping: Scalar,
+    pub angular_damping: Scalar,
+}
+
+impl Default for GrabberSettings {
+    fn default() -> Self {
+        Self {
+            max_distance: 100.0,
+            compliance: 0.000_001,
+            linear_damping: 5.0,
+            angular_damping: 1.0,
+        }
+    }
+}
 
-impl Plugin for GrabberPlugin {
-    fn build(&self, _app: &mut App) {
-        info!("Adding grabber plugin");
-        // todo: why is this borked?
-        // app.add_systems(GgrsSchedule, grab.before(step_physics));
+impl BitHash for GrabberSettings {
+    fn bit_hash<H: Hasher>(&self, ids: &RollbackIds, state: &mut H) {
+        self.max_distance.bit_hash(ids, state);
+        self.compliance.bit_hash(ids, state);
+        self.linear_damping.bit_hash(ids, state);
+        self.angular_damping.bit_hash(ids, state);
     }
 }
 
-// Hard coded for now, could probably use a resource though
-const GRAB_MIN_DISTANCE: Scalar = 100.0;
-const GRAB_COMPLIANCE: Scalar = 0.000_001;
-const GRAB_LINEAR_DAMPING: Scalar = 5.0;
-const GRAB_ANGULAR_DAMPING: Scalar = 1.0;
+/// The [`GrabberSettings`] matches start with, restored when a match is reset
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct InitialGrabberSettings(pub GrabberSettings);
 
 /// A marker component for joints used by grabbers.
-#[derive(Component)]
+#[derive(Component, Clone, Hash)]
 pub struct GrabberJoint {
-    player_handle: usize,
+    pub(crate) player_handle: usize,
 }
 
 /// The point that the grabbed entity should follow, positioned at the cursor position.
-#[derive(Component)]
+#[derive(Component, Clone, Hash)]
 pub struct Grabber {
-    player_handle: usize,
+    pub(crate) player_handle: usize,
+}
+
+impl Grabber {
+    pub fn player_handle(&self) -> usize {
+        self.player_handle
+    }
+}
+
+impl BitHash for Grabber {
+    fn bit_hash<H: Hasher>(&self, _ids: &RollbackIds, state: &mut H) {
+        self.hash(state);
+    }
+}
+
+impl BitHash for GrabberJoint {
+    fn bit_hash<H: Hasher>(&self, _ids: &RollbackIds, state: &mut H) {
+        self.hash(state);
+    }
+}
+
+pub(crate) fn grabber(player_handle: usize, position: Vector) -> impl Bundle {
+    (
+        RigidBody::Kinematic,
+        Position(position),
+        Grabber { player_handle },
+    )
 }
 
 #[allow(clippy::too_many_arguments)]
 #[allow(clippy::type_complexity)]
-pub fn grab(
+pub fn grab<C>(
     mut commands: Commands,
     mut grabbers: Query<(Entity, &Grabber, &mut Position), (With<Grabber>, Without<Collider>)>,
     joints: Query<(Entity, &GrabberJoint, &DistanceJoint)>,
     bodies: Query<(&RigidBody, &Position, &Rotation), Without<Grabber>>,
     spatial_query: SpatialQuery,
-    inputs: Res<PlayerInputs<GgrsConfig>>,
-) {
+    inputs: Res<PlayerInputs<C>>,
+    settings: Res<GrabberSettings>,
+) where
+    C: Config,
+    C::Input: GrabberInput,
+{
     for (player_handle, input) in inputs.iter().enumerate() {
-        let buttons = input.0.buttons;
+        // All peers agree on the frame a player disconnected, so their grabber
+        // is released on the same frame everywhere.
+        let connected = input.1 != InputStatus::Disconnected;
         // If grab button is pressed, spawn or update grab point and grabber joint if they don't exist
-        if buttons & INPUT_MOUSE_LEFT != 0 {
-            let cursor_world_pos = input.0.mouse_pos;
+        if connected && input.0.grabbing() {
+            let cursor_world_pos = input.0.grab_position();
             info!("mouse left held, updating grab {cursor_world_pos}");
 
             // If grabber exists, update its position, otherwise spawn it
@@ -61,11 +197,7 @@ pub fn grab(
                 entity
             } else {
                 commands
-                    .spawn((
-                        RigidBody::Kinematic,
-                        Position(cursor_world_pos),
-                        Grabber { player_handle },
-                    ))
+                    .spawn(grabber(player_handle, cursor_world_pos))
                     .add_rollback()
                     .id()
             };
@@ -83,20 +215,20 @@ pub fn grab(
                 let projection = spatial_query.project_point(cursor_world_pos, true, filter);
 
                 if let Some(projection) = projection {
-                    if projection.point.distance(cursor_world_pos) <= GRAB_MIN_DISTANCE {
+                    if projection.point.distance(cursor_world_pos) <= settings.max_distance {
                         // Spawn grabber joint
                         if let Ok((_, position, rotation)) = bodies.get(projection.entity) {
                             commands
                                 .spawn((
                                     DistanceJoint::new(grabber_entity, projection.entity)
-                                        .with_compliance(GRAB_COMPLIANCE)
+                                        .with_compliance(settings.compliance)
                                         .with_local_anchor_2(
                                             rotation
                                                 .inverse()
                                                 .rotate(projection.point - position.0),
                                         )
-                                        .with_linear_velocity_damping(GRAB_LINEAR_DAMPING)
-                                        .with_angular_velocity_damping(GRAB_ANGULAR_DAMPING),
+                                        .with_linear_velocity_damping(settings.linear_damping)
+                                        .with_angular_velocity_damping(settings.angular_damping),
                                     GrabberJoint { player_handle },
                                 ))
                                 .add_rollback();
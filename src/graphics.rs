@@ -0,0 +1,155 @@
+//! Visuals for the simulation, only added when not running headless.
+
+use bevy::{prelude::*, sprite::Mesh2dHandle};
+use bevy_ggrs::{ggrs::InputStatus, GgrsSchedule, PlayerInputs};
+use bevy_xpbd_2d::prelude::*;
+
+use crate::{
+    chat::ChatUiPlugin,
+    grabber_2d::Grabber,
+    lobby::{LobbyUiPlugin, PlayerNames},
+    viewer::ViewerPlugin,
+    GgrsConfig, Marble, Wall, MARBLE_RADIUS,
+};
+
+pub struct GraphicsPlugin;
+
+impl Plugin for GraphicsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins((ViewerPlugin, LobbyUiPlugin, ChatUiPlugin))
+            .init_resource::<PlayerCursors>()
+            .add_systems(Startup, (spawn_camera, load_marble_assets))
+            .add_systems(GgrsSchedule, track_cursors)
+            .add_systems(
+                Update,
+                (add_wall_sprites, add_marble_meshes, update_name_tags),
+            );
+    }
+}
+
+#[derive(Component)]
+pub struct MainCamera;
+
+#[derive(Resource)]
+struct MarbleAssets {
+    mesh: Handle<Mesh>,
+    material: Handle<ColorMaterial>,
+}
+
+fn spawn_camera(mut commands: Commands) {
+    commands.spawn((MainCamera, Camera2dBundle::default()));
+}
+
+fn load_marble_assets(
+    mut commands: Commands,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+) {
+    commands.insert_resource(MarbleAssets {
+        mesh: meshes.add(shape::Circle::new(MARBLE_RADIUS as f32).into()),
+        material: materials.add(ColorMaterial::from(Color::rgb(0.2, 0.7, 0.9))),
+    });
+}
+
+fn add_wall_sprites(mut commands: Commands, walls: Query<(Entity, &Wall), Added<Wall>>) {
+    for (entity, wall) in &walls {
+        commands.entity(entity).insert((
+            Sprite {
+                color: Color::rgb(0.7, 0.7, 0.8),
+                custom_size: Some(wall.size),
+                ..default()
+            },
+            Handle::<Image>::default(),
+            VisibilityBundle::default(),
+        ));
+    }
+}
+
+fn add_marble_meshes(
+    mut commands: Commands,
+    marbles: Query<Entity, Added<Marble>>,
+    marble_assets: Res<MarbleAssets>,
+) {
+    for entity in &marbles {
+        commands.entity(entity).insert((
+            Mesh2dHandle(marble_assets.mesh.clone()),
+            marble_assets.material.clone(),
+            VisibilityBundle::default(),
+        ));
+    }
+}
+
+/// Where each player's cursor was on the latest simulated frame, `None` for
+/// disconnected players.
+#[derive(Resource, Default)]
+struct PlayerCursors(Vec<Option<Vec2>>);
+
+fn track_cursors(inputs: Res<PlayerInputs<GgrsConfig>>, mut cursors: ResMut<PlayerCursors>) {
+    cursors.0 = inputs
+        .iter()
+        .map(|(input, status)| (*status != InputStatus::Disconnected).then_some(input.mouse_pos))
+        .collect();
+}
+
+#[derive(Component)]
+struct NameTag {
+    player_handle: usize,
+}
+
+/// Shows player names next to their grabber, or their cursor when they're not
+/// grabbing anything.
+fn update_name_tags(
+    mut commands: Commands,
+    names: Option<Res<PlayerNames>>,
+    cursors: Res<PlayerCursors>,
+    asset_server: Res<AssetServer>,
+    grabbers: Query<(&Grabber, &Position)>,
+    mut tags: Query<(Entity, &NameTag, &mut Transform, &mut Visibility)>,
+) {
+    let Some(names) = names else {
+        // back in the lobby
+        for (entity, ..) in &tags {
+            commands.entity(entity).despawn_recursive();
+        }
+        return;
+    };
+
+    if names.is_changed() {
+        // a new match, or players joined
+        for (entity, ..) in &tags {
+            commands.entity(entity).despawn_recursive();
+        }
+        for (player_handle, name) in names.0.iter().enumerate() {
+            commands.spawn((
+                Text2dBundle {
+                    text: Text::from_section(
+                        name.clone(),
+                        TextStyle {
+                            font: asset_server.load("fonts/quicksand-light.ttf"),
+                            font_size: 20.,
+                            color: Color::WHITE,
+                        },
+                    ),
+                    visibility: Visibility::Hidden,
+                    ..default()
+                },
+                NameTag { player_handle },
+            ));
+        }
+        return;
+    }
+
+    for (_, tag, mut transform, mut visibility) in &mut tags {
+        let grabber = grabbers
+            .iter()
+            .find(|(grabber, _)| grabber.player_handle() == tag.player_handle)
+            .map(|(_, position)| position.0);
+        let cursor = cursors.0.get(tag.player_handle).copied().flatten();
+        let Some(position) = grabber.or(cursor) else {
+            *visibility = Visibility::Hidden;
+            continue;
+        };
+        transform.translation = (position + Vec2::new(0.0, 20.0)).extend(10.0);
+        *visibility = Visibility::Inherited;
+    }
+}
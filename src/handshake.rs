@@ -0,0 +1,229 @@
+//! Verifying that peers simulate the same way before starting a session
+//!
+//! Peers with a different build, input layout, physics settings or level would
+//! desync silently. Each peer sends a [`Handshake`] describing its simulation
+//! in the lobby, and the match only starts when all of them are equal.
+
+use bevy::prelude::*;
+use bevy_ggrs::{Rollback, RollbackOrdered};
+use bevy_xpbd_2d::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{hash::Hasher, mem};
+
+use crate::{
+    checksum::Fnv1a,
+    grabber_2d::GrabberSettings,
+    input::*,
+    pause::PauseState,
+    snapshot::{self, SnapshotQuery},
+    FrameCount, SessionConfig,
+};
+
+/// Everything that has to match for peers to stay in sync
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Handshake {
+    /// The crate version and the git commit it was built from
+    pub version: String,
+    /// Hash of the size of [`GaffInput`] and its button bits
+    pub input_layout: u64,
+    pub fps: usize,
+    pub substeps: u32,
+    pub gravity: [f32; 2],
+    pub grabber: GrabberSettings,
+    /// Hash of the world we start from
+    pub level: u64,
+}
+
+impl Handshake {
+    /// Describes each setting that differs in `theirs`
+    pub fn mismatches(&self, theirs: &Handshake) -> Vec<String> {
+        let mut mismatches = Vec::new();
+        if self.version != theirs.version {
+            mismatches.push(format!(
+                "version {} instead of {}",
+                theirs.version, self.version
+            ));
+        }
+        if self.input_layout != theirs.input_layout {
+            mismatches.push("a different input layout".to_string());
+        }
+        if self.fps != theirs.fps {
+            mismatches.push(format!("fps {} instead of {}", theirs.fps, self.fps));
+        }
+        if self.substeps != theirs.substeps {
+            mismatches.push(format!(
+                "{} substeps instead of {}",
+                theirs.substeps, self.substeps
+            ));
+        }
+        if self.gravity != theirs.gravity {
+            mismatches.push(format!(
+                "gravity {:?} instead of {:?}",
+                theirs.gravity, self.gravity
+            ));
+        }
+        if self.grabber != theirs.grabber {
+            mismatches.push(format!(
+                "grabber settings {:?} instead of {:?}",
+                theirs.grabber, self.grabber
+            ));
+        }
+        if self.level != theirs.level {
+            mismatches.push("a different level or starting snapshot".to_string());
+        }
+        mismatches
+    }
+}
+
+/// The handshake of the local peer, kept up to date while in the lobby
+#[derive(Resource, Clone, Debug, PartialEq)]
+pub struct LocalHandshake(pub Handshake);
+
+fn input_layout() -> u64 {
+    let mut hash = Fnv1a::default();
+    hash.write_usize(mem::size_of::<GaffInput>());
+    hash.write_usize(mem::align_of::<GaffInput>());
+    hash.write(&[
+        INPUT_UP,
+        INPUT_DOWN,
+        INPUT_LEFT,
+        INPUT_RIGHT,
+        INPUT_MOUSE_LEFT,
+        INPUT_PAUSE,
+    ]);
+    hash.finish()
+}
+
+/// The rollback entities that changed in a way that affects the level
+#[allow(clippy::type_complexity)]
+type ChangedBodies<'w, 's> = Query<
+    'w,
+    's,
+    (),
+    (
+        With<Rollback>,
+        Or<(
+            Added<Rollback>,
+            Changed<Position>,
+            Changed<Rotation>,
+            Changed<LinearVelocity>,
+            Changed<AngularVelocity>,
+        )>,
+    ),
+>;
+
+/// Recomputes the local handshake when the settings or the level change,
+/// capturing the level is too slow to do every frame
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn update_local_handshake(
+    mut commands: Commands,
+    session_config: Res<SessionConfig>,
+    substeps: Res<SubstepCount>,
+    gravity: Res<Gravity>,
+    frame: Res<FrameCount>,
+    pause: Res<PauseState>,
+    grabber: Res<GrabberSettings>,
+    order: Res<RollbackOrdered>,
+    query: SnapshotQuery,
+    changed: ChangedBodies,
+    mut despawned: RemovedComponents<Rollback>,
+    local: Option<Res<LocalHandshake>>,
+) {
+    let settings_changed = session_config.is_changed()
+        || substeps.is_changed()
+        || gravity.is_changed()
+        || frame.is_changed()
+        || pause.is_changed()
+        || grabber.is_changed()
+        || order.is_changed();
+    let level_changed = !changed.is_empty() || despawned.iter().count() > 0;
+    if local.is_some() && !settings_changed && !level_changed {
+        return;
+    }
+
+    let level = snapshot::capture(frame.frame, &pause, &grabber, &order, &query);
+    // the grabber settings are compared on their own
+    let level = (level.frame, level.paused, level.entities);
+    let level = bincode::serialize(&level).expect("failed to serialize level");
+    let mut level_hash = Fnv1a::default();
+    level_hash.write(&level);
+
+    let handshake = Handshake {
+        version: format!(
+            "{} ({})",
+            env!("CARGO_PKG_VERSION"),
+            env!("GAFF_BUILD_HASH")
+        ),
+        input_layout: input_layout(),
+        fps: session_config.fps,
+        substeps: substeps.0,
+        gravity: [gravity.0.x, gravity.0.y],
+        grabber: *grabber,
+        level: level_hash.finish(),
+    };
+    if local.map_or(true, |local| local.0 != handshake) {
+        commands.insert_resource(LocalHandshake(handshake));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake() -> Handshake {
+        Handshake {
+            version: "0.1.0 (abc)".to_string(),
+            input_layout: input_layout(),
+            fps: 60,
+            substeps: 6,
+            gravity: [0.0, -1000.0],
+            grabber: default(),
+            level: 1,
+        }
+    }
+
+    #[test]
+    fn equal_handshakes_have_no_mismatches() {
+        assert!(handshake().mismatches(&handshake()).is_empty());
+    }
+
+    #[test]
+    fn each_mismatch_is_described() {
+        let ours = handshake();
+        let theirs = Handshake {
+            version: "0.1.0 (def)".to_string(),
+            fps: 30,
+            level: 2,
+            ..handshake()
+        };
+        assert_eq!(
+            ours.mismatches(&theirs),
+            [
+                "version 0.1.0 (def) instead of 0.1.0 (abc)",
+                "fps 30 instead of 60",
+                "a different level or starting snapshot",
+            ]
+        );
+    }
+
+    #[test]
+    fn physics_and_input_mismatches_are_found() {
+        let ours = handshake();
+        let theirs = Handshake {
+            input_layout: ours.input_layout + 1,
+            substeps: 12,
+            gravity: [0.0, -500.0],
+            grabber: GrabberSettings {
+                max_distance: 50.0,
+                ..default()
+            },
+            ..handshake()
+        };
+        let mismatches = ours.mismatches(&theirs);
+        assert_eq!(mismatches.len(), 4);
+        assert_eq!(mismatches[0], "a different input layout");
+        assert_eq!(mismatches[1], "12 substeps instead of 6");
+        assert!(mismatches[2].starts_with("gravity [0.0, -500.0]"));
+        assert!(mismatches[3].starts_with("grabber settings"));
+    }
+}
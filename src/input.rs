@@ -3,8 +3,12 @@ use bevy::prelude::*;
 use bevy::utils::HashMap;
 use bevy::window::PrimaryWindow;
 use bevy_ggrs::{LocalInputs, LocalPlayers};
+use bevy_xpbd_2d::math::Vector;
 
-use crate::{GgrsConfig, MainCamera};
+use crate::{
+    chat::ChatFocus, grabber_2d::GrabberInput, graphics::MainCamera, pause::PauseRequest,
+    GgrsConfig,
+};
 
 #[repr(C)]
 #[derive(Copy, Clone, PartialEq, Pod, Zeroable, Debug, Default, Reflect)]
@@ -19,43 +23,70 @@ pub const INPUT_DOWN: u8 = 1 << 1;
 pub const INPUT_LEFT: u8 = 1 << 2;
 pub const INPUT_RIGHT: u8 = 1 << 3;
 pub const INPUT_MOUSE_LEFT: u8 = 1 << 4;
+/// Held while the player wants the match paused, see [`crate::pause`]
+pub const INPUT_PAUSE: u8 = 1 << 5;
 
+impl GrabberInput for GaffInput {
+    fn grabbing(&self) -> bool {
+        self.buttons & INPUT_MOUSE_LEFT != 0
+    }
+
+    fn grab_position(&self) -> Vector {
+        self.mouse_pos
+    }
+}
+
+/// Reads local input from the keyboard and mouse.
+///
+/// Window, camera and input resources are optional, so this also works
+/// headless, where it just sends empty input.
+#[allow(clippy::too_many_arguments)]
 pub fn input(
     mut commands: Commands,
-    keyboard: Res<Input<KeyCode>>,
+    keyboard: Option<Res<Input<KeyCode>>>,
     windows: Query<&Window, With<PrimaryWindow>>,
     cameras: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
-    mouse_buttons: Res<Input<MouseButton>>,
+    mouse_buttons: Option<Res<Input<MouseButton>>>,
     local_players: Res<LocalPlayers>,
+    pause_request: Res<PauseRequest>,
+    chat_focus: Res<ChatFocus>,
 ) {
     let mut local_inputs = HashMap::new();
 
     let mut input: u8 = 0;
 
-    if keyboard.pressed(KeyCode::W) {
-        input |= INPUT_UP;
-    }
-    if keyboard.pressed(KeyCode::A) {
-        input |= INPUT_LEFT;
-    }
-    if keyboard.pressed(KeyCode::S) {
-        input |= INPUT_DOWN;
-    }
-    if keyboard.pressed(KeyCode::D) {
-        input |= INPUT_RIGHT;
+    // typing in the chat doesn't move marbles
+    if let Some(keyboard) = keyboard.filter(|_| !chat_focus.0) {
+        if keyboard.pressed(KeyCode::W) {
+            input |= INPUT_UP;
+        }
+        if keyboard.pressed(KeyCode::A) {
+            input |= INPUT_LEFT;
+        }
+        if keyboard.pressed(KeyCode::S) {
+            input |= INPUT_DOWN;
+        }
+        if keyboard.pressed(KeyCode::D) {
+            input |= INPUT_RIGHT;
+        }
     }
 
-    if mouse_buttons.pressed(MouseButton::Left) {
+    if mouse_buttons.is_some_and(|buttons| buttons.pressed(MouseButton::Left)) {
         input |= INPUT_MOUSE_LEFT;
     }
 
-    let (camera, camera_transform) = cameras.single();
-    let mouse_pos = windows
-        .single()
-        .cursor_position()
-        .and_then(|cursor| camera.viewport_to_world(camera_transform, cursor))
-        .map(|ray| ray.origin.truncate())
-        .unwrap_or(Vec2::ZERO);
+    if pause_request.requested {
+        input |= INPUT_PAUSE;
+    }
+
+    let mouse_pos = match (cameras.get_single(), windows.get_single()) {
+        (Ok((camera, camera_transform)), Ok(window)) => window
+            .cursor_position()
+            .and_then(|cursor| camera.viewport_to_world(camera_transform, cursor))
+            .map(|ray| ray.origin.truncate())
+            .unwrap_or(Vec2::ZERO),
+        _ => Vec2::ZERO,
+    };
 
     let gaff_input = GaffInput {
         buttons: input,
@@ -0,0 +1,526 @@
+//! Networked (p2p) rollback physics for Bevy, using bevy_ggrs, bevy_xpbd and
+//! matchbox.
+//!
+//! Add [`GaffPlugin`] to an app with the default plugins (or [`HeadlessPlugins`]
+//! without a window), and insert an [`Args`] resource to configure the lobby.
+
+use bevy::ecs::schedule::ScheduleLabel;
+use bevy::{app::PluginGroupBuilder, prelude::*};
+use bevy_ggrs::{prelude::*, RollbackOrdered};
+use bevy_matchbox::prelude::*;
+use bevy_xpbd_2d::{math::*, prelude::*};
+use std::hash::{Hash, Hasher};
+
+use args::Args;
+use chat::ChatPlugin;
+use checksum::{BitHash, GgrsComponentChecksumBitHashPlugin, RollbackIds};
+use connection::{ConnectionPlugin, PeerConnections};
+use desync::{DesyncPlugin, Desynced, FrameDumps};
+use grabber_2d::{GrabberPlugin, GrabberSet, GrabberSettings, InitialGrabberSettings};
+use graphics::GraphicsPlugin;
+use input::*;
+use lobby::LobbyPlugin;
+use pause::{PausePlugin, PauseRequest, PauseState};
+use replay::{Playback, PlaybackPlugin, RecordPlugin, Recorder, RecordingFailed};
+use rollback::RollbackApp;
+use snapshot::{SnapshotHistory, SnapshotPlugin, StartingSnapshot};
+use viewer::{CheckpointPlugin, ComponentCheckpointPlugin, ReplayViewer};
+
+pub mod args;
+pub mod chat;
+pub mod checksum;
+pub mod connection;
+pub mod desync;
+pub mod grabber_2d;
+pub mod graphics;
+pub mod handshake;
+pub mod input;
+pub mod lobby;
+pub mod netsim;
+pub mod pause;
+pub mod replay;
+pub mod rollback;
+pub mod snapshot;
+pub mod viewer;
+
+pub const FPS: usize = 60;
+
+pub type GgrsConfig = bevy_ggrs::GgrsConfig<GaffInput, PeerId>;
+
+/// Sets up rollback physics: the GGRS session, the physics schedule, input
+/// and rollback registration.
+#[derive(Clone, Debug)]
+pub struct GaffPlugin {
+    pub session: SessionConfig,
+    pub substep_count: u32,
+    pub gravity: Vector,
+    /// The grabber settings matches start with
+    pub grabber: GrabberSettings,
+    /// Whether to read local input from the keyboard and mouse.
+    ///
+    /// Disable this to provide your own [`ReadInputs`] system.
+    pub local_input: bool,
+    /// Run without a window or renderer, see [`HeadlessPlugins`].
+    pub headless: bool,
+}
+
+impl GaffPlugin {
+    /// Takes the session and physics settings from the command line or config
+    pub fn from_args(args: &Args) -> Self {
+        Self {
+            session: SessionConfig {
+                fps: args.fps,
+                max_prediction_window: args.max_prediction_window,
+                input_delay: args.input_delay,
+            },
+            substep_count: args.substeps,
+            gravity: Vector::NEG_Y * args.gravity,
+            grabber: GrabberSettings {
+                max_distance: args.grab_max_distance,
+                compliance: args.grab_compliance,
+                linear_damping: args.grab_linear_damping,
+                angular_damping: args.grab_angular_damping,
+            },
+            headless: args.headless,
+            ..default()
+        }
+    }
+}
+
+impl Default for GaffPlugin {
+    fn default() -> Self {
+        Self {
+            session: default(),
+            substep_count: 6,
+            gravity: Vector::NEG_Y * 1000.0,
+            grabber: default(),
+            local_input: true,
+            headless: false,
+        }
+    }
+}
+
+impl Plugin for GaffPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins((
+            PhysicsPlugins::new(PhysicsSchedule),
+            LobbyPlugin,
+            DesyncPlugin,
+            ConnectionPlugin,
+            ChatPlugin,
+            PausePlugin,
+            SnapshotPlugin,
+            RecordPlugin,
+            PlaybackPlugin,
+        ))
+        .add_plugins(GgrsPlugin::<GgrsConfig>::default())
+        .add_plugins(CheckpointPlugin)
+        .add_plugins(GrabberPlugin::<GgrsConfig>::new(self.grabber))
+        .add_plugins(GgrsComponentChecksumBitHashPlugin::<Position>::default())
+        .add_plugins(GgrsComponentChecksumBitHashPlugin::<Rotation>::default())
+        .add_plugins(GgrsComponentChecksumBitHashPlugin::<LinearVelocity>::default())
+        .add_plugins(GgrsComponentChecksumBitHashPlugin::<AngularVelocity>::default())
+        // not rolled back by ggrs, but checkpoints need them to respawn grabbers
+        .add_plugins(ComponentCheckpointPlugin::<RigidBody>::default())
+        .insert_resource(self.session)
+        .insert_resource(SubstepCount(self.substep_count))
+        .insert_resource(Gravity(self.gravity))
+        .insert_resource(PhysicsTimestep::FixedOnce(1. / self.session.fps as f32))
+        .init_resource::<FrameCount>()
+        .init_resource::<SessionFrameOffset>()
+        // Some of our systems need the query parameters
+        .init_resource::<Args>()
+        .add_state::<AppState>()
+        .add_systems(
+            Startup,
+            (
+                setup,
+                setup_scene,
+                spawn_marbles,
+                apply_deferred,
+                snapshot::load_snapshot_file,
+            )
+                .chain(),
+        )
+        .add_systems(OnEnter(AppState::Lobby), reset_match)
+        .add_systems(
+            Update,
+            log_ggrs_events
+                .run_if(in_match)
+                .run_if(resource_exists::<Session<GgrsConfig>>()),
+        )
+        // these systems will be executed as part of the advance frame update
+        .add_systems(
+            GgrsSchedule,
+            (
+                // ideally these systems should be part of the rollback schedule, but seems it breaks
+                // synctest sessions for some reason... should investigate...
+                // setup_scene,
+                // spawn_marbles,
+                pause::update_pause,
+                (step_physics.in_set(PhysicsStepSet), movement).run_if(pause::simulation_running),
+                increase_frame_system,
+                desync::record_frame_dump,
+                snapshot::record_snapshot,
+                apply_deferred,
+                viewer::save_checkpoint,
+            )
+                .chain(),
+        )
+        .configure_set(
+            GgrsSchedule,
+            GrabberSet
+                .after(pause::update_pause)
+                .before(PhysicsStepSet)
+                .run_if(pause::simulation_running),
+        );
+
+        app.rollback_resource::<FrameCount>()
+            .rollback_resource::<PauseState>()
+            .rollback_component::<Transform>()
+            .rollback_component::<Position>()
+            .rollback_component::<PreviousPosition>()
+            .rollback_component::<LinearVelocity>()
+            .rollback_component::<Rotation>()
+            .rollback_component::<PreviousRotation>()
+            .rollback_component::<AngularVelocity>();
+
+        if self.local_input {
+            app.add_systems(ReadInputs, input.run_if(not(resource_exists::<Playback>())));
+        }
+
+        if !self.headless {
+            app.add_plugins(GraphicsPlugin);
+        }
+    }
+}
+
+/// A minimal plugin set for running the simulation without a window or
+/// renderer, to be used with a headless [`GaffPlugin`] instead of
+/// [`DefaultPlugins`].
+pub struct HeadlessPlugins;
+
+impl PluginGroup for HeadlessPlugins {
+    fn build(self) -> PluginGroupBuilder {
+        MinimalPlugins
+            .build()
+            .add(TransformPlugin)
+            .add(HierarchyPlugin)
+    }
+}
+
+/// Settings used when building GGRS sessions, see [`configure_session`].
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct SessionConfig {
+    pub fps: usize,
+    pub max_prediction_window: usize,
+    pub input_delay: usize,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            fps: FPS,
+            max_prediction_window: 12,
+            // TODO: re-enable input delay when rollbacks are working properly
+            // input_delay: 2,
+            input_delay: 0,
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct Marble;
+
+#[derive(Resource, Clone, Copy, Debug, Default, Reflect, Hash, Deref, DerefMut)]
+#[reflect(Resource, Hash)]
+pub struct FrameCount {
+    pub frame: usize,
+}
+
+impl BitHash for FrameCount {
+    fn bit_hash<H: Hasher>(&self, _ids: &RollbackIds, state: &mut H) {
+        self.hash(state);
+    }
+}
+
+/// The [`FrameCount`] the current session started on. GGRS counts frames from
+/// zero in every session, so this is added to the frames it reports.
+#[derive(Resource, Clone, Copy, Debug, Default)]
+pub struct SessionFrameOffset(pub usize);
+
+/// A static wall, `size` is the full extents of its cuboid collider.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Wall {
+    pub size: Vec2,
+}
+
+pub(crate) fn wall(position: Vector, size: Vec2) -> impl Bundle {
+    (
+        TransformBundle::from_transform(Transform::from_translation(position.extend(0.0))),
+        RigidBody::Static,
+        Position(position),
+        Collider::cuboid(size.x as Scalar, size.y as Scalar),
+        Wall { size },
+    )
+}
+
+fn setup_scene(mut commands: Commands, frame: Res<FrameCount>) {
+    if **frame != 0 {
+        return;
+    }
+
+    spawn_walls(&mut commands);
+}
+
+fn spawn_walls(commands: &mut Commands) {
+    info!("Setting up scene");
+
+    // Ceiling
+    commands
+        .spawn(wall(Vector::Y * 50.0 * 6.0, Vec2::new(50.0 * 20.0, 50.0)))
+        .add_rollback();
+
+    // Floor
+    commands
+        .spawn(wall(
+            Vector::NEG_Y * 50.0 * 6.0,
+            Vec2::new(50.0 * 20.0, 50.0),
+        ))
+        .add_rollback();
+
+    // Left wall
+    commands
+        .spawn(wall(
+            Vector::NEG_X * 50.0 * 9.5,
+            Vec2::new(50.0, 50.0 * 11.0),
+        ))
+        .add_rollback();
+
+    // Right wall
+    commands
+        .spawn(wall(Vector::X * 50.0 * 9.5, Vec2::new(50.0, 50.0 * 11.0)))
+        .add_rollback();
+}
+
+pub const MARBLE_RADIUS: Scalar = 10.0;
+
+pub(crate) fn marble(position: Vector) -> impl Bundle {
+    (
+        TransformBundle::from_transform(Transform::from_translation(position.extend(0.0))),
+        RigidBody::Dynamic,
+        Position(position),
+        Rotation::default(),
+        Collider::ball(MARBLE_RADIUS),
+        Friction::new(0.0),
+        Marble,
+    )
+}
+
+fn spawn_marbles(mut commands: Commands, frame_count: Res<FrameCount>) {
+    if **frame_count != 0 {
+        info!("not spawning marbles on frame {frame_count:?}");
+        return;
+    }
+
+    spawn_marble_stacks(&mut commands);
+}
+
+fn spawn_marble_stacks(commands: &mut Commands) {
+    info!("Spawning marbles");
+
+    let half_width = 5;
+    let half_height = 5;
+
+    // Spawn stacks of marbles
+    for x in -half_width..=half_width {
+        for y in -half_height..=half_height {
+            let position = Vector::new(
+                x as Scalar * (2.5 * MARBLE_RADIUS),
+                y as Scalar * (2.5 * MARBLE_RADIUS),
+            );
+            commands.spawn(marble(position)).add_rollback();
+        }
+    }
+}
+
+pub fn movement(
+    inputs: Res<PlayerInputs<GgrsConfig>>,
+    mut marbles: Query<&mut LinearVelocity, With<Marble>>,
+) {
+    for input in inputs.iter() {
+        let buttons = input.0.buttons;
+        for mut linear_velocity in &mut marbles {
+            if buttons & INPUT_UP != 0 {
+                linear_velocity.y += 50.0;
+            }
+            if buttons & INPUT_DOWN != 0 {
+                linear_velocity.y -= 10.0;
+            }
+            if buttons & INPUT_LEFT != 0 {
+                linear_velocity.x -= 10.0;
+            }
+            if buttons & INPUT_RIGHT != 0 {
+                linear_velocity.x += 10.0;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash, States)]
+pub enum AppState {
+    #[default]
+    Startup,
+    Lobby,
+    InGame,
+    Paused,
+}
+
+/// Run condition for systems that run during a match, paused or not
+pub fn in_match(state: Res<State<AppState>>) -> bool {
+    matches!(state.get(), AppState::InGame | AppState::Paused)
+}
+
+#[derive(ScheduleLabel, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct PhysicsSchedule;
+
+fn setup(
+    mut commands: Commands,
+    mut app_state: ResMut<NextState<AppState>>,
+    args: Res<Args>,
+    session_config: Res<SessionConfig>,
+    session: Option<Res<Session<GgrsConfig>>>,
+) {
+    if session.is_some() {
+        info!("using existing session");
+        app_state.set(AppState::InGame)
+    } else if let Some(path) = &args.replay {
+        if let Err(e) = replay::start_playback(&mut commands, path, &session_config) {
+            error!("failed to play back {path:?}: {e}");
+            std::process::exit(1);
+        }
+        if !args.headless {
+            commands.init_resource::<ReplayViewer>();
+        }
+        app_state.set(AppState::InGame)
+    } else if args.players == 1 {
+        info!("starting synctest session");
+        let mut session_builder = configure_session(1, &session_config);
+        session_builder = session_builder
+            .add_player(PlayerType::Local, 0)
+            .expect("failed to add player");
+        let session = session_builder
+            .start_synctest_session()
+            .expect("failed to start synctest session");
+        commands.insert_resource(Session::SyncTest(session));
+        app_state.set(AppState::InGame)
+    } else {
+        info!("joining multiplayer lobby");
+        app_state.set(AppState::Lobby)
+    }
+}
+
+pub fn configure_session(players: usize, config: &SessionConfig) -> SessionBuilder<GgrsConfig> {
+    SessionBuilder::<GgrsConfig>::new()
+        .with_num_players(players)
+        .with_max_prediction_window(config.max_prediction_window)
+        .with_input_delay(config.input_delay)
+        .with_fps(config.fps)
+        .expect("invalid fps")
+}
+
+fn log_ggrs_events(
+    mut commands: Commands,
+    mut session: ResMut<Session<GgrsConfig>>,
+    frame_dumps: Res<FrameDumps>,
+    desynced: Option<Res<Desynced>>,
+    mut connections: ResMut<PeerConnections>,
+    frame_offset: Res<SessionFrameOffset>,
+) {
+    match session.as_mut() {
+        Session::P2P(s) => {
+            for event in s.events() {
+                info!("GGRS Event: {event:?}");
+                if let GgrsEvent::DesyncDetected { frame, .. } = event {
+                    error!("desynced on frame {frame}!");
+                    // only report the first desync, everything after it is garbage anyway
+                    if desynced.is_none() {
+                        let frame = frame as usize + frame_offset.0;
+                        let player = s.local_player_handles().first().copied().unwrap_or(0);
+                        let dump_path = desync::write_frame_dump(&frame_dumps, frame, player);
+                        commands.insert_resource(Desynced { frame, dump_path });
+                    }
+                } else {
+                    connections.handle_event(&event);
+                }
+            }
+        }
+        Session::Spectator(s) => {
+            for event in s.events() {
+                info!("GGRS Event: {event:?}");
+                connections.handle_event(&event);
+            }
+        }
+        Session::SyncTest(_) => {}
+    }
+}
+
+/// Ends the current match when returning to the lobby, and resets the world
+/// so the next session starts from the same state on all peers.
+fn reset_match(
+    mut commands: Commands,
+    mut frame: ResMut<FrameCount>,
+    rollbacks: Query<Entity, With<Rollback>>,
+    starting_snapshot: Option<Res<StartingSnapshot>>,
+    initial_grabber: Res<InitialGrabberSettings>,
+    recorder: Option<ResMut<Recorder>>,
+) {
+    // every match is recorded to its own file
+    if let Some(mut recorder) = recorder {
+        recorder.finish();
+        commands.remove_resource::<Recorder>();
+    }
+    commands.remove_resource::<RecordingFailed>();
+
+    commands.remove_resource::<Session<GgrsConfig>>();
+    commands.remove_resource::<Desynced>();
+    commands.insert_resource(PeerConnections::default());
+
+    // the world hasn't changed if the match ended before its first frame
+    let starting_frame = starting_snapshot.as_ref().map_or(0, |s| s.0.frame);
+    if frame.frame == starting_frame {
+        return;
+    }
+
+    info!("resetting match after {} frames", frame.frame);
+    commands.insert_resource(FrameDumps::default());
+    commands.insert_resource(SnapshotHistory::default());
+    commands.insert_resource(SessionFrameOffset::default());
+    commands.insert_resource(PauseState::default());
+    commands.insert_resource(PauseRequest::default());
+    commands.insert_resource(initial_grabber.0);
+    if let Some(starting_snapshot) = starting_snapshot {
+        starting_snapshot.0.restore(&mut commands, &rollbacks);
+        return;
+    }
+    for entity in &rollbacks {
+        commands.entity(entity).despawn_recursive();
+    }
+    // peers that joined late have a shorter rollback order than the others
+    commands.insert_resource(RollbackOrdered::default());
+    frame.frame = 0;
+    spawn_walls(&mut commands);
+    spawn_marble_stacks(&mut commands);
+}
+
+pub(crate) fn increase_frame_system(mut frame_count: ResMut<FrameCount>) {
+    frame_count.frame += 1;
+}
+
+/// The set [`step_physics`] runs in, in the rollback schedule
+#[derive(SystemSet, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct PhysicsStepSet;
+
+pub fn step_physics(world: &mut World) {
+    world.run_schedule(PhysicsSchedule);
+}
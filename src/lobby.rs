@@ -1,7 +1,197 @@
-use crate::{args::Args, configure_session, AppState};
-use bevy::prelude::*;
-use bevy_ggrs::{ggrs::DesyncDetection, Session};
-use bevy_matchbox::prelude::*;
+use crate::{
+    args::Args,
+    configure_session,
+    connection::PeerConnections,
+    handshake::{self, Handshake, LocalHandshake},
+    netsim::{NetworkConditions, SimulatedSocket},
+    snapshot::{SnapshotHistory, WorldSnapshot},
+    AppState, FrameCount, GgrsConfig, SessionConfig, SessionFrameOffset,
+};
+use bevy::{
+    app::AppExit,
+    prelude::*,
+    utils::{HashMap, HashSet, Instant},
+};
+use bevy_ggrs::{
+    ggrs::{DesyncDetection, Message, NonBlockingSocket, PlayerType},
+    Rollback, Session,
+};
+use bevy_inspector_egui::bevy_egui::{egui, EguiContexts, EguiPlugin};
+use bevy_matchbox::{
+    matchbox_socket::{MessageLoopFuture, WebRtcChannel},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// The unreliable channel GGRS runs on
+const GGRS_CHANNEL: usize = 0;
+/// A reliable channel for messages between peers in the lobby
+const LOBBY_CHANNEL: usize = 1;
+/// A reliable channel for chat messages, see [`crate::chat`]
+pub(crate) const CHAT_CHANNEL: usize = 2;
+
+/// How long to wait for the matchbox server to assign us an id
+const SIGNALING_TIMEOUT: Duration = Duration::from_secs(10);
+/// The delay before the first retry, doubles with every failed attempt
+const SIGNALING_RETRY_DELAY: Duration = Duration::from_secs(1);
+const SIGNALING_MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Longer names are cut off
+const MAX_NAME_LENGTH: usize = 20;
+
+/// Whether a peer is playing or just watching
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Player,
+    Spectator,
+}
+
+/// Messages sent over the lobby channel
+#[derive(Serialize, Deserialize, Debug)]
+enum LobbyMessage {
+    /// Sent to every peer that connects
+    Hello { role: Role, in_match: bool },
+    /// Sent to every peer that connects, and when it changes
+    Status { name: String, ready: bool },
+    /// Sent to every peer that connects, and when it changes
+    Handshake(Handshake),
+    /// Sent by the host when starting before the maximum number of players
+    /// joined, with `players` by handle
+    Start { players: Vec<PeerId> },
+    /// Sent by the host when peers join a match in progress. Everyone restores
+    /// the snapshot and starts a new session, with `players` by handle.
+    Resume {
+        players: Vec<PeerId>,
+        snapshot: WorldSnapshot,
+    },
+}
+
+impl LobbyMessage {
+    fn send(&self, socket: &mut MatchboxSocket<MultipleChannels>, peer: PeerId) {
+        let packet = bincode::serialize(self).expect("failed to serialize lobby message");
+        socket
+            .channel(LOBBY_CHANNEL)
+            .send(packet.into_boxed_slice(), peer);
+    }
+}
+
+/// The connected peers, as far as they have announced themselves
+#[derive(Resource, Default)]
+pub(crate) struct LobbyPeers {
+    roles: HashMap<PeerId, Role>,
+    /// Peers that are already playing a match
+    in_match: HashSet<PeerId>,
+    /// Peers that want to join the match we're playing
+    joining: HashSet<PeerId>,
+    names: HashMap<PeerId, String>,
+    ready: HashSet<PeerId>,
+    handshakes: HashMap<PeerId, Handshake>,
+}
+
+impl LobbyPeers {
+    fn with_role(&self, role: Role) -> Vec<PeerId> {
+        let mut peers: Vec<_> = self
+            .roles
+            .iter()
+            .filter(|(_, r)| **r == role)
+            .map(|(peer, _)| *peer)
+            .collect();
+        peers.sort();
+        peers
+    }
+
+    fn remove(&mut self, peer: PeerId) {
+        self.roles.remove(&peer);
+        self.in_match.remove(&peer);
+        self.joining.remove(&peer);
+        self.names.remove(&peer);
+        self.ready.remove(&peer);
+        self.handshakes.remove(&peer);
+    }
+
+    fn set_status(&mut self, peer: PeerId, name: &str, ready: bool) {
+        self.names.insert(peer, sanitize_name(name));
+        if ready {
+            self.ready.insert(peer);
+        } else {
+            self.ready.remove(&peer);
+        }
+    }
+
+    pub(crate) fn name(&self, peer: PeerId) -> String {
+        display_name(self.names.get(&peer).map_or("", String::as_str), peer)
+    }
+}
+
+fn sanitize_name(name: &str) -> String {
+    name.trim().chars().take(MAX_NAME_LENGTH).collect()
+}
+
+/// Falls back to the start of the peer id for players without a name
+pub(crate) fn display_name(name: &str, peer: PeerId) -> String {
+    let name = sanitize_name(name);
+    if name.is_empty() {
+        peer.0.to_string()[..8].to_string()
+    } else {
+        name
+    }
+}
+
+/// The name of the local player, and whether they are ready to start
+#[derive(Resource, Clone, Debug)]
+pub struct LocalPlayer {
+    pub name: String,
+    pub role: Role,
+    pub ready: bool,
+    /// The host wants to start without waiting for more players
+    pub start_early: bool,
+}
+
+/// What the local player can do in the lobby
+#[derive(Resource, Default, Debug)]
+struct LobbyStatus {
+    /// The match is full without us
+    surplus: bool,
+    /// We're the host and enough players are ready
+    can_start_early: bool,
+    /// Why we can't play with some of the peers
+    mismatches: Vec<String>,
+}
+
+impl LocalPlayer {
+    fn status(&self) -> LobbyMessage {
+        LobbyMessage::Status {
+            name: self.name.clone(),
+            ready: self.ready,
+        }
+    }
+}
+
+/// The display names of the players in the current match, by player handle
+#[derive(Resource, Clone, Debug, Default)]
+pub struct PlayerNames(pub Vec<String>);
+
+/// The peers playing the current match, indexed by player handle
+#[derive(Resource, Clone, Debug)]
+pub struct MatchPlayers(pub Vec<PeerId>);
+
+/// The GGRS channel, shared so a new session can take over when peers join
+#[derive(Resource, Clone)]
+struct GgrsChannel(Arc<Mutex<WebRtcChannel>>);
+
+impl NonBlockingSocket<PeerId> for GgrsChannel {
+    fn send_to(&mut self, msg: &Message, addr: &PeerId) {
+        self.0.lock().unwrap().send_to(msg, addr);
+    }
+
+    fn receive_all_messages(&mut self) -> Vec<(PeerId, Message)> {
+        self.0.lock().unwrap().receive_all_messages()
+    }
+}
 
 /// Marker component
 #[derive(Component)]
@@ -18,12 +208,110 @@ impl Plugin for LobbyPlugin {
             OnEnter(AppState::Lobby),
             (lobby_startup, start_matchbox_socket),
         )
-        .add_systems(Update, lobby_system.run_if(in_state(AppState::Lobby)))
+        .add_systems(
+            Update,
+            (
+                check_signaling.run_if(resource_exists::<Signaling>()),
+                handshake::update_local_handshake,
+                lobby_system.run_if(resource_exists::<MatchboxSocket<MultipleChannels>>()),
+            )
+                .chain()
+                .run_if(in_state(AppState::Lobby)),
+        )
+        .add_systems(
+            Update,
+            match_system
+                .run_if(crate::in_match)
+                .run_if(resource_exists::<MatchPlayers>())
+                .run_if(resource_exists::<Session<GgrsConfig>>())
+                .run_if(resource_exists::<MatchboxSocket<MultipleChannels>>()),
+        )
         .add_systems(OnExit(AppState::Lobby), lobby_cleanup);
     }
 }
 
-fn start_matchbox_socket(mut commands: Commands, args: Res<Args>) {
+/// Lets players enter their name and ready up in the lobby.
+///
+/// Without it, e.g. when running headless, players are ready right away.
+pub struct LobbyUiPlugin;
+
+impl Plugin for LobbyUiPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<EguiPlugin>() {
+            app.add_plugins(EguiPlugin);
+        }
+        app.insert_resource(LobbyUi).add_systems(
+            Update,
+            lobby_ui
+                .run_if(in_state(AppState::Lobby))
+                .run_if(resource_exists::<LocalPlayer>())
+                .run_if(resource_exists::<LobbyStatus>()),
+        );
+    }
+}
+
+/// Marks that players ready up through the lobby UI
+#[derive(Resource)]
+struct LobbyUi;
+
+/// The connection to the matchbox signaling server
+#[derive(Resource)]
+struct Signaling {
+    /// Set when the socket's message loop ends
+    error: Arc<Mutex<Option<String>>>,
+    connecting_since: Instant,
+    /// Failed attempts in a row
+    attempts: u32,
+    /// Why the last attempt failed, and when to try again
+    failure: Option<(String, Instant)>,
+    /// Edited in the lobby UI, used once the player reconnects
+    server: String,
+    room: String,
+}
+
+impl Signaling {
+    fn fail(&mut self, error: String) {
+        let delay = SIGNALING_RETRY_DELAY * 2u32.pow(self.attempts.min(5));
+        let delay = delay.min(SIGNALING_MAX_RETRY_DELAY);
+        error!("signaling failed: {error}, retrying in {delay:?}");
+        self.attempts += 1;
+        self.failure = Some((error, Instant::now() + delay));
+    }
+}
+
+fn start_matchbox_socket(
+    mut commands: Commands,
+    args: Res<Args>,
+    local_player: Option<Res<LocalPlayer>>,
+    lobby_ui: Option<Res<LobbyUi>>,
+) {
+    if let Some(error) = lobby_error(&args) {
+        error!("{error}");
+        return;
+    }
+
+    open_socket(&mut commands, &args, 0);
+    // keep the name from the last match, but ready up again
+    commands.insert_resource(LocalPlayer {
+        name: local_player
+            .map_or_else(|| args.name.clone().unwrap_or_default(), |p| p.name.clone()),
+        role: local_role(&args),
+        ready: lobby_ui.is_none(),
+        start_early: false,
+    });
+    commands.insert_resource(LobbyStatus::default());
+}
+
+/// Why the lobby can't be entered with these arguments, shown instead of
+/// connecting
+fn lobby_error(args: &Args) -> Option<&'static str> {
+    // spectators would be matched up as players in an automatic room, clap
+    // checks this on native, but not the query string on wasm
+    (args.room.is_none() && args.spectate).then_some("Spectating requires a room")
+}
+
+/// Connects to the matchbox server, replacing any previous socket
+fn open_socket(commands: &mut Commands, args: &Args, attempts: u32) {
     let room_id = match &args.room {
         Some(id) => id.clone(),
         None => format!("bevy_ggrs?next={}", &args.players),
@@ -32,10 +320,98 @@ fn start_matchbox_socket(mut commands: Commands, args: Res<Args>) {
     let room_url = format!("{}/{}", &args.matchbox, room_id);
     info!("connecting to matchbox server: {room_url:?}");
 
-    commands.insert_resource(MatchboxSocket::new_ggrs(room_url));
+    let (socket, message_loop) = WebRtcSocketBuilder::new(room_url)
+        .add_ggrs_channel()
+        .add_reliable_channel()
+        .add_reliable_channel()
+        .build();
+
+    // the socket doesn't tell us why it stopped working, so catch it here
+    let error = Arc::new(Mutex::new(None));
+    let loop_error = error.clone();
+    let message_loop: MessageLoopFuture = Box::pin(async move {
+        let result = message_loop.await;
+        let message = match &result {
+            Ok(()) => "the signaling server closed the connection".to_string(),
+            Err(err) => err.to_string(),
+        };
+        *loop_error.lock().unwrap() = Some(message);
+        result
+    });
+
+    let mut socket = MatchboxSocket::from((socket, message_loop));
+    let channel = socket.take_channel(GGRS_CHANNEL).unwrap();
+    commands.insert_resource(GgrsChannel(Arc::new(Mutex::new(channel))));
+    commands.insert_resource(socket);
+    commands.insert_resource(Signaling {
+        error,
+        connecting_since: Instant::now(),
+        attempts,
+        failure: None,
+        server: args.matchbox.clone(),
+        room: args.room.clone().unwrap_or_default(),
+    });
+    commands.remove_resource::<MatchPlayers>();
+    commands.remove_resource::<PlayerNames>();
+    commands.insert_resource(LobbyPeers::default());
 }
 
-fn lobby_startup(mut commands: Commands, asset_server: Res<AssetServer>) {
+/// Detects when the matchbox server can't be reached, and retries with
+/// increasing delays.
+fn check_signaling(
+    mut commands: Commands,
+    args: Res<Args>,
+    mut signaling: ResMut<Signaling>,
+    socket: Option<Res<MatchboxSocket<MultipleChannels>>>,
+    mut query: Query<&mut Text, With<LobbyText>>,
+) {
+    if let Some((error, retry_at)) = &signaling.failure {
+        let remaining = retry_at.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            let attempts = signaling.attempts;
+            open_socket(&mut commands, &args, attempts);
+            return;
+        }
+        for mut text in &mut query {
+            text.sections[0].value = format!(
+                "Can't connect to {}:\n{error}\nRetrying in {}s",
+                args.matchbox,
+                remaining.as_secs_f32().ceil()
+            );
+        }
+        return;
+    }
+
+    let Some(socket) = socket else {
+        return;
+    };
+    let error = signaling.error.lock().unwrap().take();
+    if let Some(error) = error {
+        signaling.fail(error);
+    } else if socket.id().is_some() {
+        // the server assigned us an id, so we're connected
+        if signaling.attempts > 0 {
+            info!("connected to the matchbox server");
+            signaling.attempts = 0;
+        }
+        return;
+    } else if signaling.connecting_since.elapsed() > SIGNALING_TIMEOUT {
+        signaling.fail("timed out".to_string());
+    } else {
+        return;
+    }
+
+    // drops the connection, if there still is one
+    commands.remove_resource::<MatchboxSocket<MultipleChannels>>();
+    commands.remove_resource::<GgrsChannel>();
+}
+
+fn lobby_startup(mut commands: Commands, args: Res<Args>, asset_server: Option<Res<AssetServer>>) {
+    // No UI when running headless
+    let Some(asset_server) = asset_server else {
+        return;
+    };
+
     // All this is just for spawning centered text.
     commands
         .spawn(NodeBundle {
@@ -59,10 +435,10 @@ fn lobby_startup(mut commands: Commands, asset_server: Res<AssetServer>) {
                         ..default()
                     },
                     text: Text::from_section(
-                        "Entering lobby...",
+                        lobby_error(&args).unwrap_or("Entering lobby..."),
                         TextStyle {
                             font: asset_server.load("fonts/quicksand-light.ttf"),
-                            font_size: 96.,
+                            font_size: 48.,
                             color: Color::BLACK,
                         },
                     ),
@@ -79,52 +455,994 @@ fn lobby_cleanup(query: Query<Entity, With<LobbyUI>>, mut commands: Commands) {
     }
 }
 
+fn lobby_ui(
+    mut contexts: EguiContexts,
+    mut args: ResMut<Args>,
+    status: Res<LobbyStatus>,
+    signaling: Option<ResMut<Signaling>>,
+    mut local_player: ResMut<LocalPlayer>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let mut signaling = signaling.filter(|signaling| signaling.failure.is_some());
+
+    // spectators don't need to get ready
+    if local_player.role == Role::Spectator && signaling.is_none() {
+        return;
+    }
+
+    egui::Window::new("Lobby")
+        .anchor(egui::Align2::CENTER_TOP, [0.0, 20.0])
+        .collapsible(false)
+        .resizable(false)
+        .show(contexts.ctx_mut(), |ui| {
+            if let Some(signaling) = &mut signaling {
+                signaling_ui(ui, &mut args, signaling);
+                return;
+            }
+
+            // can't take it back, or peers could start without us
+            ui.add_enabled_ui(!local_player.ready, |ui| {
+                let mut name = local_player.name.clone();
+                ui.horizontal(|ui| {
+                    ui.label("Name");
+                    ui.add(egui::TextEdit::singleline(&mut name).char_limit(MAX_NAME_LENGTH));
+                });
+                // only notify peers when it actually changed
+                if name != local_player.name {
+                    local_player.name = name;
+                }
+                if ui.button("Ready").clicked() {
+                    local_player.ready = true;
+                }
+            });
+
+            for mismatch in &status.mismatches {
+                ui.colored_label(egui::Color32::RED, mismatch);
+            }
+
+            if status.surplus {
+                ui.label("The match is full");
+                ui.horizontal(|ui| {
+                    if ui.button("Spectate").clicked() {
+                        local_player.role = Role::Spectator;
+                    }
+                    if ui.button("Leave").clicked() {
+                        exit.send(AppExit);
+                    }
+                });
+            }
+
+            if status.can_start_early && ui.button("Start now").clicked() {
+                local_player.start_early = true;
+            }
+        });
+}
+
+/// Lets players fix the server URL or room when the server can't be reached
+fn signaling_ui(ui: &mut egui::Ui, args: &mut Args, signaling: &mut Signaling) {
+    let Some((error, retry_at)) = &signaling.failure else {
+        return;
+    };
+    let remaining = retry_at.saturating_duration_since(Instant::now());
+    ui.label(format!("Can't connect to {}: {error}", args.matchbox));
+    ui.label(format!("Retrying in {}s", remaining.as_secs_f32().ceil()));
+
+    egui::Grid::new("signaling").show(ui, |ui| {
+        ui.label("Server");
+        ui.text_edit_singleline(&mut signaling.server);
+        ui.end_row();
+        ui.label("Room");
+        ui.text_edit_singleline(&mut signaling.room);
+        ui.end_row();
+    });
+
+    if ui.button("Connect").clicked() {
+        args.matchbox = signaling.server.trim().to_string();
+        let room = signaling.room.trim();
+        args.room = (!room.is_empty()).then(|| room.to_string());
+        signaling.attempts = 0;
+        signaling.failure = Some((String::new(), Instant::now()));
+    }
+}
+
+fn local_role(args: &Args) -> Role {
+    if args.spectate {
+        Role::Spectator
+    } else {
+        Role::Player
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn lobby_system(
     mut app_state: ResMut<NextState<AppState>>,
     args: Res<Args>,
-    mut socket: ResMut<MatchboxSocket<SingleChannel>>,
+    mut socket: ResMut<MatchboxSocket<MultipleChannels>>,
+    mut peers: ResMut<LobbyPeers>,
+    local_player: Res<LocalPlayer>,
+    local_handshake: Option<Res<LocalHandshake>>,
+    mut status: ResMut<LobbyStatus>,
     mut commands: Commands,
     mut query: Query<&mut Text, With<LobbyText>>,
+    session_config: Res<SessionConfig>,
+    channel: Res<GgrsChannel>,
+    rollbacks: Query<Entity, With<Rollback>>,
 ) {
+    let hello = LobbyMessage::Hello {
+        role: local_player.role,
+        in_match: false,
+    };
+
     // regularly call update_peers to update the list of connected peers
     for (peer, new_state) in socket.update_peers() {
         // you can also handle the specific dis(connections) as they occur:
         match new_state {
-            PeerState::Connected => info!("peer {peer} connected"),
-            PeerState::Disconnected => info!("peer {peer} disconnected"),
+            PeerState::Connected => {
+                info!("peer {peer} connected");
+                hello.send(&mut socket, peer);
+                local_player.status().send(&mut socket, peer);
+                if let Some(LocalHandshake(handshake)) = local_handshake.as_deref() {
+                    LobbyMessage::Handshake(handshake.clone()).send(&mut socket, peer);
+                }
+            }
+            PeerState::Disconnected => {
+                info!("peer {peer} disconnected");
+                peers.remove(peer);
+            }
+        }
+    }
+
+    if local_player.is_changed() {
+        let peers: Vec<_> = socket.connected_peers().collect();
+        for peer in peers {
+            // the role changes when surplus players decide to spectate
+            hello.send(&mut socket, peer);
+            local_player.status().send(&mut socket, peer);
+        }
+    }
+
+    if let Some(local_handshake) = local_handshake.as_ref().filter(|h| h.is_changed()) {
+        let peers: Vec<_> = socket.connected_peers().collect();
+        for peer in peers {
+            LobbyMessage::Handshake(local_handshake.0.clone()).send(&mut socket, peer);
+        }
+    }
+
+    let Some(local_id) = socket.id() else {
+        return;
+    };
+
+    let mut start = None;
+    for (peer, message) in socket.channel(LOBBY_CHANNEL).receive() {
+        match bincode::deserialize(&message) {
+            Ok(LobbyMessage::Hello { role, in_match }) => {
+                info!("peer {peer} joined as {role:?}");
+                peers.roles.insert(peer, role);
+                if in_match {
+                    peers.in_match.insert(peer);
+                }
+            }
+            Ok(LobbyMessage::Status { name, ready }) => {
+                peers.set_status(peer, &name, ready);
+            }
+            Ok(LobbyMessage::Handshake(handshake)) => {
+                peers.handshakes.insert(peer, handshake);
+            }
+            Ok(LobbyMessage::Start { players }) => {
+                info!("{peer} started the match with {} player(s)", players.len());
+                start = Some((peer, players));
+            }
+            Ok(LobbyMessage::Resume { players, snapshot }) => {
+                info!("joining match in progress on frame {}", snapshot.frame);
+                let resume = Resume {
+                    host: peer,
+                    players,
+                    snapshot,
+                };
+                resume.apply(&mut commands, &rollbacks);
+                let names = player_names(&resume.players, &peers, local_id, &local_player);
+                commands.insert_resource(names);
+                start_session(
+                    &mut commands,
+                    &args,
+                    &session_config,
+                    &channel,
+                    local_id,
+                    resume.host,
+                    &resume.players,
+                    &[],
+                );
+                app_state.set(AppState::InGame);
+                return;
+            }
+            Err(err) => warn!("invalid lobby message from {peer}: {err}"),
         }
     }
 
-    let connected_peers = socket.connected_peers().count();
-    let remaining = args.players - (connected_peers + 1);
-    query.single_mut().sections[0].value = format!("Waiting for {remaining} more player(s)",);
-    if remaining > 0 {
+    let (host, players) = match start {
+        Some(start) => start,
+        None => {
+            let unannounced = socket
+                .connected_peers()
+                .filter(|peer| !peers.roles.contains_key(peer))
+                .count();
+            let mut lobby_text = String::new();
+            let players = players_to_start(
+                &args,
+                unannounced,
+                &peers,
+                &local_player,
+                local_id,
+                local_handshake.as_deref(),
+                &mut status,
+                &mut lobby_text,
+            );
+            for mut text in query.iter_mut() {
+                text.sections[0].value = lobby_text.clone();
+            }
+            let Some(players) = players else {
+                return;
+            };
+            if players.len() < args.players {
+                // peers can't tell on their own that we're not waiting for more
+                let message = LobbyMessage::Start {
+                    players: players.clone(),
+                };
+                let peers: Vec<_> = socket.connected_peers().collect();
+                for peer in peers {
+                    message.send(&mut socket, peer);
+                }
+            }
+            (players[0], players)
+        }
+    };
+
+    if !players.contains(&local_id) && local_player.role == Role::Player {
+        // started without us, wait for a slot to free up
+        peers.in_match.extend(players.iter().copied());
         return;
     }
 
-    info!("All peers have joined, going in-game");
+    info!("All players are ready, going in-game");
+
+    // players that didn't make it into the match can join once a slot frees up
+    let spectators = peers.with_role(Role::Spectator);
+    let left_out: Vec<_> = socket
+        .connected_peers()
+        .filter(|peer| !players.contains(peer) && !spectators.contains(peer))
+        .collect();
+    for peer in left_out {
+        let hello = LobbyMessage::Hello {
+            role: local_player.role,
+            in_match: true,
+        };
+        hello.send(&mut socket, peer);
+        peers.joining.insert(peer);
+    }
+
+    commands.insert_resource(player_names(&players, &peers, local_id, &local_player));
+
+    // the host sends confirmed inputs to spectators
+    start_session(
+        &mut commands,
+        &args,
+        &session_config,
+        &channel,
+        local_id,
+        host,
+        &players,
+        &spectators,
+    );
+
+    // transition to in-game state
+    app_state.set(AppState::InGame);
+}
+
+/// Returns the players, by handle, once the match can start.
+///
+/// Players beyond the maximum are left out, and the first player, the host,
+/// can start early once the minimum is reached. Everyone has to simulate the
+/// same way, see [`crate::handshake`].
+#[allow(clippy::too_many_arguments)]
+fn players_to_start(
+    args: &Args,
+    unannounced: usize,
+    peers: &LobbyPeers,
+    local_player: &LocalPlayer,
+    local_id: PeerId,
+    local_handshake: Option<&LocalHandshake>,
+    status: &mut LobbyStatus,
+    lobby_text: &mut String,
+) -> Option<Vec<PeerId>> {
+    *status = LobbyStatus::default();
+
+    // wait for the host to send us the state of the match
+    if !peers.in_match.is_empty() {
+        let message = if local_player.role == Role::Spectator {
+            "Joining match in progress..."
+        } else if !local_player.ready {
+            "Get ready to join the match in progress"
+        } else {
+            status.surplus = true;
+            "Waiting for a free slot in the match in progress..."
+        };
+        *lobby_text = message.to_string();
+        return None;
+    }
+
+    // handles are assigned in peer id order, so all peers agree on them
+    let mut players = peers.with_role(Role::Player);
+    if local_player.role == Role::Player {
+        players.push(local_id);
+        players.sort();
+    }
+    let surplus = players.split_off(players.len().min(args.players));
+    status.surplus = surplus.contains(&local_id);
+
+    let min_players = args.min_players();
+    let is_ready = |peer: &PeerId| {
+        if *peer == local_id {
+            local_player.ready
+        } else {
+            peers.ready.contains(peer)
+        }
+    };
+    let all_ready = players.iter().all(is_ready);
+    let full = players.len() == args.players;
+    let is_host = players.first() == Some(&local_id);
+
+    // everyone taking part has to simulate the same way
+    let spectators = peers.with_role(Role::Spectator);
+    let mut handshakes_pending = local_handshake.is_none();
+    if let Some(LocalHandshake(local)) = local_handshake {
+        for &peer in players.iter().chain(&spectators) {
+            if peer == local_id {
+                continue;
+            }
+            match peers.handshakes.get(&peer) {
+                Some(theirs) => {
+                    let name = peers.name(peer);
+                    for mismatch in local.mismatches(theirs) {
+                        status.mismatches.push(format!("{name} has {mismatch}"));
+                    }
+                }
+                None => handshakes_pending = true,
+            }
+        }
+    }
+    let compatible = !handshakes_pending && status.mismatches.is_empty();
+
+    status.can_start_early =
+        is_host && !full && all_ready && compatible && players.len() >= min_players;
 
-    // extract final player list
-    let players = socket.players();
+    let mut lines = vec![if status.surplus {
+        "The match is full".to_string()
+    } else if !status.mismatches.is_empty() {
+        "Can't start, not everyone simulates the same way".to_string()
+    } else if players.len() < min_players {
+        format!("Waiting for {} more player(s)", min_players - players.len())
+    } else if !full && all_ready {
+        format!(
+            "Waiting for up to {} more player(s), or the host to start",
+            args.players - players.len()
+        )
+    } else {
+        "Waiting for everyone to get ready".to_string()
+    }];
+    for peer in &players {
+        let name = if *peer == local_id {
+            display_name(&local_player.name, local_id)
+        } else {
+            peers.name(*peer)
+        };
+        let ready = if is_ready(peer) { "ready" } else { "not ready" };
+        lines.push(format!("{name}: {ready}"));
+    }
+    lines.extend(status.mismatches.iter().cloned());
+    *lobby_text = lines.join("\n");
 
-    let mut session_builder = configure_session(args.players);
+    let start_early = status.can_start_early && local_player.start_early;
+    if unannounced > 0 || !all_ready || !compatible || !(full || start_early) {
+        return None;
+    }
+    Some(players)
+}
+
+fn player_names(
+    players: &[PeerId],
+    peers: &LobbyPeers,
+    local_id: PeerId,
+    local_player: &LocalPlayer,
+) -> PlayerNames {
+    let names = players
+        .iter()
+        .map(|&peer| {
+            if peer == local_id {
+                display_name(&local_player.name, local_id)
+            } else {
+                peers.name(peer)
+            }
+        })
+        .collect();
+    PlayerNames(names)
+}
 
-    for (i, player) in players.into_iter().enumerate() {
+/// Starts a GGRS session with `players` by handle, where `host` sends
+/// confirmed inputs to spectators.
+#[allow(clippy::too_many_arguments)]
+fn start_session(
+    commands: &mut Commands,
+    args: &Args,
+    session_config: &SessionConfig,
+    channel: &GgrsChannel,
+    local_id: PeerId,
+    host: PeerId,
+    players: &[PeerId],
+    spectators: &[PeerId],
+) {
+    let mut session_builder = configure_session(players.len(), session_config)
+        .with_desync_detection_mode(DesyncDetection::On { interval: 1 });
+
+    let conditions = NetworkConditions::from_args(args);
+    if !conditions.is_perfect() {
+        warn!("simulating network conditions: {conditions:?}");
+    }
+    let channel = SimulatedSocket::new(channel.clone(), conditions);
+
+    commands.insert_resource(MatchPlayers(players.to_vec()));
+
+    if !players.contains(&local_id) {
+        info!("spectating {host}");
+        let session = session_builder.start_spectator_session(host, channel);
+        commands.insert_resource(Session::Spectator(session));
+        return;
+    }
+
+    for (handle, &peer) in players.iter().enumerate() {
+        let player = if peer == local_id {
+            PlayerType::Local
+        } else {
+            PlayerType::Remote(peer)
+        };
         session_builder = session_builder
-            .add_player(player, i)
+            .add_player(player, handle)
             .expect("failed to add player");
     }
 
-    let channel = socket.take_channel(0).unwrap();
+    if local_id == host {
+        // spectator handles come after the player handles
+        for (i, &spectator) in spectators.iter().enumerate() {
+            session_builder = session_builder
+                .add_player(PlayerType::Spectator(spectator), players.len() + i)
+                .expect("failed to add spectator");
+        }
+    }
 
     // start the GGRS session
     let session = session_builder
-        .with_desync_detection_mode(DesyncDetection::On { interval: 1 })
         .start_p2p_session(channel)
         .expect("failed to start session");
 
     commands.insert_resource(Session::P2P(session));
+}
 
-    // transition to in-game state
-    app_state.set(AppState::InGame);
+/// A match restarting from a snapshot, with new peers
+struct Resume {
+    host: PeerId,
+    players: Vec<PeerId>,
+    snapshot: WorldSnapshot,
+}
+
+impl Resume {
+    fn apply(&self, commands: &mut Commands, rollbacks: &Query<Entity, With<Rollback>>) {
+        self.snapshot.restore(commands, rollbacks);
+        commands.insert_resource(PeerConnections::default());
+    }
+}
+
+/// Lets peers join while a match is running.
+///
+/// The host, the first connected player, picks a free player handle for each
+/// joining player, and sends everyone a snapshot of the last confirmed frame.
+/// All peers then restore it and start a new session.
+#[allow(clippy::too_many_arguments)]
+fn match_system(
+    mut commands: Commands,
+    args: Res<Args>,
+    session_config: Res<SessionConfig>,
+    mut socket: ResMut<MatchboxSocket<MultipleChannels>>,
+    mut peers: ResMut<LobbyPeers>,
+    local_player: Res<LocalPlayer>,
+    local_handshake: Option<Res<LocalHandshake>>,
+    match_players: Res<MatchPlayers>,
+    session: Res<Session<GgrsConfig>>,
+    history: Res<SnapshotHistory>,
+    frame_offset: Res<SessionFrameOffset>,
+    frame: Res<FrameCount>,
+    channel: Res<GgrsChannel>,
+    rollbacks: Query<Entity, With<Rollback>>,
+) {
+    for (peer, new_state) in socket.update_peers() {
+        match new_state {
+            PeerState::Connected => {
+                info!("peer {peer} connected during the match");
+                let hello = LobbyMessage::Hello {
+                    role: local_player.role,
+                    in_match: true,
+                };
+                hello.send(&mut socket, peer);
+                local_player.status().send(&mut socket, peer);
+                if let Some(LocalHandshake(handshake)) = local_handshake.as_deref() {
+                    LobbyMessage::Handshake(handshake.clone()).send(&mut socket, peer);
+                }
+            }
+            PeerState::Disconnected => {
+                info!("peer {peer} disconnected");
+                peers.remove(peer);
+            }
+        }
+    }
+
+    let Some(local_id) = socket.id() else {
+        return;
+    };
+
+    let mut resume = None;
+    for (peer, message) in socket.channel(LOBBY_CHANNEL).receive() {
+        match bincode::deserialize(&message) {
+            Ok(LobbyMessage::Hello { role, in_match }) => {
+                peers.roles.insert(peer, role);
+                if in_match {
+                    peers.in_match.insert(peer);
+                } else {
+                    info!("peer {peer} wants to join as {role:?}");
+                    peers.joining.insert(peer);
+                }
+            }
+            Ok(LobbyMessage::Status { name, ready }) => {
+                peers.set_status(peer, &name, ready);
+            }
+            Ok(LobbyMessage::Handshake(handshake)) => {
+                peers.handshakes.insert(peer, handshake);
+            }
+            // the match has already started
+            Ok(LobbyMessage::Start { .. }) => {}
+            Ok(LobbyMessage::Resume { players, snapshot }) => {
+                resume = Some(Resume {
+                    host: peer,
+                    players,
+                    snapshot,
+                });
+            }
+            Err(err) => warn!("invalid lobby message from {peer}: {err}"),
+        }
+    }
+
+    let connected: HashSet<_> = socket.connected_peers().collect();
+    let is_connected = |peer: &PeerId| *peer == local_id || connected.contains(peer);
+    let host = match_players.0.iter().copied().find(is_connected);
+
+    let confirmed_frame = match session.as_ref() {
+        Session::P2P(session) => usize::try_from(session.confirmed_frame()).ok(),
+        _ => None,
+    };
+    if resume.is_none() && host == Some(local_id) && !peers.joining.is_empty() {
+        resume = host_resume(
+            local_id,
+            args.players,
+            confirmed_frame,
+            &history,
+            &frame_offset,
+            &match_players,
+            &mut peers,
+            local_handshake.as_deref(),
+            &is_connected,
+        );
+        if let Some(resume) = &resume {
+            for peer in connected.iter().copied() {
+                let message = LobbyMessage::Resume {
+                    players: resume.players.clone(),
+                    snapshot: resume.snapshot.clone(),
+                };
+                message.send(&mut socket, peer);
+            }
+        }
+    }
+
+    let Some(resume) = resume else {
+        return;
+    };
+
+    info!(
+        "resuming on frame {} from frame {} with {:?}",
+        resume.snapshot.frame, frame.frame, resume.players
+    );
+    resume.apply(&mut commands, &rollbacks);
+    commands.insert_resource(player_names(
+        &resume.players,
+        &peers,
+        local_id,
+        &local_player,
+    ));
+    let mut spectators = peers.with_role(Role::Spectator);
+    spectators.retain(|peer| connected.contains(peer));
+    start_session(
+        &mut commands,
+        &args,
+        &session_config,
+        &channel,
+        local_id,
+        resume.host,
+        &resume.players,
+        &spectators,
+    );
+}
+
+/// Assigns joining players to the handles of disconnected ones, or new handles
+/// up to `max_players`, and picks a snapshot of the session's last
+/// `confirmed_frame` to resume from.
+#[allow(clippy::too_many_arguments)]
+fn host_resume(
+    local_id: PeerId,
+    max_players: usize,
+    confirmed_frame: Option<usize>,
+    history: &SnapshotHistory,
+    frame_offset: &SessionFrameOffset,
+    match_players: &MatchPlayers,
+    peers: &mut LobbyPeers,
+    local_handshake: Option<&LocalHandshake>,
+    is_connected: &impl Fn(&PeerId) -> bool,
+) -> Option<Resume> {
+    // players join once they are ready, spectators right away, as long as they
+    // simulate the same way we do
+    let compatible = |peer: &PeerId| {
+        let theirs = peers.handshakes.get(peer);
+        local_handshake.is_some_and(|local| Some(&local.0) == theirs)
+    };
+    let mut joining: Vec<_> = peers
+        .joining
+        .iter()
+        .copied()
+        .filter(|peer| {
+            peers.ready.contains(peer) || peers.roles.get(peer) == Some(&Role::Spectator)
+        })
+        .filter(compatible)
+        .collect();
+    joining.sort();
+    let mut players = match_players.0.clone();
+    let mut joined = Vec::new();
+    for peer in joining {
+        if peers.roles.get(&peer) == Some(&Role::Spectator) {
+            // spectators are added by starting a new session as well
+            joined.push(peer);
+        } else if let Some(free) = players.iter_mut().find(|p| !is_connected(p)) {
+            info!("{peer} takes over the slot of {free}");
+            *free = peer;
+            joined.push(peer);
+        } else if players.len() < max_players {
+            // the match started early, with fewer players than it has room for
+            info!("{peer} joins as player {}", players.len());
+            players.push(peer);
+            joined.push(peer);
+        }
+        // otherwise, the player waits until a slot frees up
+    }
+
+    // GGRS can't start a session with players that are gone
+    if joined.is_empty() || !players.iter().all(is_connected) {
+        return None;
+    }
+
+    // the state after advancing the last confirmed frame is final
+    let frame = confirmed_frame? + 1 + frame_offset.0;
+    let Some(snapshot) = history.get(frame) else {
+        warn!("no snapshot of confirmed frame {frame}, can't resume yet");
+        return None;
+    };
+
+    for peer in &joined {
+        peers.joining.remove(peer);
+    }
+
+    Some(Resume {
+        host: local_id,
+        players,
+        snapshot: snapshot.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn peer(id: u128) -> PeerId {
+        PeerId(Uuid::from_u128(id))
+    }
+
+    fn handshake() -> Handshake {
+        Handshake {
+            version: "1".to_string(),
+            input_layout: 1,
+            fps: 60,
+            substeps: 6,
+            gravity: [0.0, -1000.0],
+            grabber: default(),
+            level: 1,
+        }
+    }
+
+    fn args(players: usize, min_players: Option<usize>) -> Args {
+        Args {
+            players,
+            min_players,
+            ..default()
+        }
+    }
+
+    fn local_player(ready: bool) -> LocalPlayer {
+        LocalPlayer {
+            name: "local".to_string(),
+            role: Role::Player,
+            ready,
+            start_early: false,
+        }
+    }
+
+    /// Peers that announced themselves with a matching handshake
+    fn lobby(players: &[PeerId], ready: bool) -> LobbyPeers {
+        let mut peers = LobbyPeers::default();
+        for &peer in players {
+            peers.roles.insert(peer, Role::Player);
+            peers.set_status(peer, "", ready);
+            peers.handshakes.insert(peer, handshake());
+        }
+        peers
+    }
+
+    fn start(
+        args: &Args,
+        peers: &LobbyPeers,
+        local_player: &LocalPlayer,
+        local_id: PeerId,
+    ) -> (Option<Vec<PeerId>>, LobbyStatus) {
+        let mut status = LobbyStatus::default();
+        let players = players_to_start(
+            args,
+            0,
+            peers,
+            local_player,
+            local_id,
+            Some(&LocalHandshake(handshake())),
+            &mut status,
+            &mut String::new(),
+        );
+        (players, status)
+    }
+
+    #[test]
+    fn handles_are_assigned_in_peer_id_order() {
+        let peers = lobby(&[peer(3), peer(1)], true);
+        let (players, status) = start(&args(3, None), &peers, &local_player(true), peer(2));
+        assert_eq!(players, Some(vec![peer(1), peer(2), peer(3)]));
+        assert!(!status.surplus);
+    }
+
+    #[test]
+    fn waits_until_everyone_is_ready() {
+        let peers = lobby(&[peer(1)], false);
+        let (players, _) = start(&args(2, None), &peers, &local_player(true), peer(2));
+        assert_eq!(players, None);
+
+        let peers = lobby(&[peer(1)], true);
+        let (players, _) = start(&args(2, None), &peers, &local_player(false), peer(2));
+        assert_eq!(players, None);
+    }
+
+    #[test]
+    fn waits_for_unannounced_peers() {
+        let peers = lobby(&[peer(1)], true);
+        let mut status = LobbyStatus::default();
+        let players = players_to_start(
+            &args(2, None),
+            1,
+            &peers,
+            &local_player(true),
+            peer(2),
+            Some(&LocalHandshake(handshake())),
+            &mut status,
+            &mut String::new(),
+        );
+        assert_eq!(players, None);
+    }
+
+    #[test]
+    fn players_beyond_the_maximum_are_surplus() {
+        let peers = lobby(&[peer(1), peer(2)], true);
+        let (players, status) = start(&args(2, None), &peers, &local_player(true), peer(3));
+        assert_eq!(players, Some(vec![peer(1), peer(2)]));
+        assert!(status.surplus);
+
+        let (players, status) = start(&args(2, None), &peers, &local_player(true), peer(0));
+        assert_eq!(players, Some(vec![peer(0), peer(1)]));
+        assert!(!status.surplus);
+    }
+
+    #[test]
+    fn the_host_can_start_early_with_the_minimum() {
+        let peers = lobby(&[peer(2)], true);
+        let mut host = local_player(true);
+
+        let (players, status) = start(&args(4, Some(3)), &peers, &host, peer(1));
+        assert_eq!(players, None);
+        assert!(!status.can_start_early, "below the minimum");
+
+        let (players, status) = start(&args(4, Some(2)), &peers, &host, peer(1));
+        assert_eq!(players, None, "waits for the host to start");
+        assert!(status.can_start_early);
+
+        host.start_early = true;
+        let (players, _) = start(&args(4, Some(2)), &peers, &host, peer(1));
+        assert_eq!(players, Some(vec![peer(1), peer(2)]));
+
+        // only the host decides
+        let (_, status) = start(&args(4, Some(2)), &peers, &host, peer(3));
+        assert!(!status.can_start_early);
+    }
+
+    #[test]
+    fn mismatched_handshakes_prevent_starting() {
+        let mut peers = lobby(&[peer(1)], true);
+        peers.handshakes.get_mut(&peer(1)).unwrap().fps = 30;
+        let (players, status) = start(&args(2, None), &peers, &local_player(true), peer(2));
+        assert_eq!(players, None);
+        assert_eq!(status.mismatches.len(), 1);
+    }
+
+    fn snapshot(frame: usize) -> WorldSnapshot {
+        WorldSnapshot {
+            frame,
+            paused: false,
+            grabber: default(),
+            entities: Vec::new(),
+        }
+    }
+
+    /// Peer 1 hosts a match of `match_players`, peer 3 wants to join
+    fn resume(
+        max_players: usize,
+        match_players: &[PeerId],
+        connected: &[PeerId],
+        peers: &mut LobbyPeers,
+    ) -> Option<Resume> {
+        let mut history = SnapshotHistory::default();
+        history.insert(snapshot(110));
+        history.insert(snapshot(111));
+        host_resume(
+            peer(1),
+            max_players,
+            Some(10),
+            &history,
+            &SessionFrameOffset(100),
+            &MatchPlayers(match_players.to_vec()),
+            peers,
+            Some(&LocalHandshake(handshake())),
+            &|peer| connected.contains(peer),
+        )
+    }
+
+    fn joining(ready: bool) -> LobbyPeers {
+        let mut peers = lobby(&[peer(3)], ready);
+        peers.joining.insert(peer(3));
+        peers
+    }
+
+    #[test]
+    fn joiners_take_over_disconnected_slots() {
+        let mut peers = joining(true);
+        let resume = resume(2, &[peer(1), peer(2)], &[peer(1), peer(3)], &mut peers)
+            .expect("peer 3 should take over the slot of peer 2");
+        assert_eq!(resume.host, peer(1));
+        assert_eq!(resume.players, [peer(1), peer(3)]);
+        // the state after the confirmed frame, in FrameCount
+        assert_eq!(resume.snapshot.frame, 111);
+        assert!(peers.joining.is_empty());
+    }
+
+    #[test]
+    fn joiners_are_added_to_matches_with_room() {
+        let mut peers = joining(true);
+        let all = [peer(1), peer(2), peer(3)];
+        let resume = resume(3, &[peer(1), peer(2)], &all, &mut peers)
+            .expect("the match started with a free slot");
+        assert_eq!(resume.players, all);
+    }
+
+    #[test]
+    fn joiners_wait_for_a_free_slot() {
+        let mut peers = joining(true);
+        let all = [peer(1), peer(2), peer(3)];
+        assert!(resume(2, &[peer(1), peer(2)], &all, &mut peers).is_none());
+        assert!(peers.joining.contains(&peer(3)));
+    }
+
+    #[test]
+    fn joiners_need_to_be_ready_and_compatible() {
+        let all = [peer(1), peer(2), peer(3)];
+        let mut peers = joining(false);
+        assert!(resume(3, &[peer(1), peer(2)], &all, &mut peers).is_none());
+
+        let mut peers = joining(true);
+        peers.handshakes.get_mut(&peer(3)).unwrap().level = 2;
+        assert!(resume(3, &[peer(1), peer(2)], &all, &mut peers).is_none());
+    }
+
+    #[test]
+    fn spectators_join_without_a_slot() {
+        let mut peers = joining(false);
+        peers.roles.insert(peer(3), Role::Spectator);
+        let all = [peer(1), peer(2), peer(3)];
+        let resume =
+            resume(2, &[peer(1), peer(2)], &all, &mut peers).expect("spectators join right away");
+        assert_eq!(resume.players, [peer(1), peer(2)]);
+    }
+
+    #[test]
+    fn resuming_needs_a_confirmed_snapshot() {
+        let mut peers = joining(true);
+        let connected = [peer(1), peer(3)];
+        let resumed = host_resume(
+            peer(1),
+            2,
+            None,
+            &SnapshotHistory::default(),
+            &SessionFrameOffset(0),
+            &MatchPlayers(vec![peer(1), peer(2)]),
+            &mut peers,
+            Some(&LocalHandshake(handshake())),
+            &|peer| connected.contains(peer),
+        );
+        assert!(resumed.is_none());
+        assert!(peers.joining.contains(&peer(3)), "tries again later");
+    }
+
+    /// How long after now `fail` schedules the next attempt
+    fn retry_delay(signaling: &mut Signaling) -> Duration {
+        let before = Instant::now();
+        signaling.fail("test".to_string());
+        let (_, retry_at) = signaling.failure.clone().expect("no retry scheduled");
+        // the time spent in fail is negligible next to whole seconds
+        Duration::from_secs((retry_at - before).as_secs_f64().round() as u64)
+    }
+
+    #[test]
+    fn signaling_retries_back_off_up_to_the_maximum() {
+        let mut signaling = Signaling {
+            error: default(),
+            connecting_since: Instant::now(),
+            attempts: 0,
+            failure: None,
+            server: String::new(),
+            room: String::new(),
+        };
+        let delays: Vec<_> = (0..8)
+            .map(|_| retry_delay(&mut signaling).as_secs())
+            .collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 30, 30, 30]);
+        assert_eq!(signaling.attempts, 8);
+        assert_eq!(signaling.failure.unwrap().0, "test");
+    }
+
+    #[test]
+    fn spectating_needs_a_room() {
+        let spectator = Args {
+            spectate: true,
+            ..default()
+        };
+        assert!(lobby_error(&spectator).is_some());
+
+        let room = Some("room".to_string());
+        assert!(lobby_error(&Args { room, ..spectator }).is_none());
+        assert!(lobby_error(&args(2, None)).is_none());
+    }
 }
@@ -1,327 +1,79 @@
-use crate::{input::*, lobby::LobbyPlugin};
-use args::*;
-use bevy::ecs::schedule::ScheduleLabel;
+use bevy::app::ScheduleRunnerPlugin;
 use bevy::log::LogPlugin;
-use bevy::{diagnostic::FrameTimeDiagnosticsPlugin, prelude::*, sprite::MaterialMesh2dBundle};
-use bevy_ggrs::{
-    prelude::*, GgrsComponentChecksumHashPlugin, GgrsComponentMapEntitiesPlugin,
-    GgrsComponentSnapshotClonePlugin, GgrsResourceSnapshotClonePlugin,
+use bevy::{diagnostic::FrameTimeDiagnosticsPlugin, prelude::*};
+use bevy_gaff::{
+    args::{Args, Command},
+    desync::diff_dumps,
+    GaffPlugin, HeadlessPlugins,
 };
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
-use bevy_matchbox::prelude::*;
-use bevy_xpbd_2d::{math::*, prelude::*};
-use grabber_2d::GrabberPlugin;
+use std::{path::Path, time::Duration};
 
-mod args;
-mod grabber_2d;
-mod input;
-mod lobby;
-
-const FPS: usize = 60;
-
-pub type GgrsConfig = bevy_ggrs::GgrsConfig<GaffInput, PeerId>;
-
-#[derive(Component)]
-struct Marble;
-
-/// just used for desync detection for now
-#[derive(Component, Clone, Copy, Default, Reflect)]
-#[reflect(Component, Hash)]
-struct PrevPos(Vec2);
-
-#[derive(Component)]
-pub struct MainCamera;
-
-impl std::hash::Hash for PrevPos {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.0.x.to_bits().hash(state);
-        self.0.y.to_bits().hash(state);
-    }
-}
-
-#[derive(Resource, Clone, Copy, Debug, Default, Reflect, Hash, Deref, DerefMut)]
-#[reflect(Resource, Hash)]
-struct FrameCount {
-    frame: usize,
-}
-
-fn setup_scene(mut commands: Commands, frame: Res<FrameCount>) {
-    if **frame != 0 {
-        return;
-    }
-
-    info!("Setting up scene");
-    let square_sprite = Sprite {
-        color: Color::rgb(0.7, 0.7, 0.8),
-        custom_size: Some(Vec2::splat(50.0)),
-        ..default()
-    };
-
-    // Ceiling
-    commands
-        .spawn((
-            SpriteBundle {
-                sprite: square_sprite.clone(),
-                transform: Transform::from_scale(Vec3::new(20.0, 1.0, 1.0)),
-                ..default()
-            },
-            RigidBody::Static,
-            Position(Vector::Y * 50.0 * 6.0),
-            Collider::cuboid(50.0 * 20.0, 50.0),
-        ))
-        .add_rollback();
-
-    // Floor
-    commands
-        .spawn((
-            SpriteBundle {
-                sprite: square_sprite.clone(),
-                transform: Transform::from_scale(Vec3::new(20.0, 1.0, 1.0)),
-                ..default()
-            },
-            RigidBody::Static,
-            Position(Vector::NEG_Y * 50.0 * 6.0),
-            Collider::cuboid(50.0 * 20.0, 50.0),
-        ))
-        .add_rollback();
-
-    // Left wall
-    commands
-        .spawn((
-            SpriteBundle {
-                sprite: square_sprite.clone(),
-                transform: Transform::from_scale(Vec3::new(1.0, 11.0, 1.0)),
-                ..default()
-            },
-            RigidBody::Static,
-            Position(Vector::NEG_X * 50.0 * 9.5),
-            Collider::cuboid(50.0, 50.0 * 11.0),
-        ))
-        .add_rollback();
-
-    // Right wall
-    commands
-        .spawn((
-            SpriteBundle {
-                sprite: square_sprite,
-                transform: Transform::from_scale(Vec3::new(1.0, 11.0, 1.0)),
-                ..default()
-            },
-            RigidBody::Static,
-            Position(Vector::X * 50.0 * 9.5),
-            Collider::cuboid(50.0, 50.0 * 11.0),
-        ))
-        .add_rollback();
-}
+fn main() {
+    // read query string or command line arguments
+    let args = Args::get();
+    info!("{args:?}");
 
-fn spawn_marbles(
-    mut commands: Commands,
-    frame_count: Res<FrameCount>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
-    mut meshes: ResMut<Assets<Mesh>>,
-) {
-    if **frame_count != 0 {
-        info!("not spawning marbles on frame {frame_count:?}");
+    if let Some(command) = &args.command {
+        run_command(command);
         return;
     }
-    info!("Spawning marbles");
 
-    let marble_radius = 10.0;
-    let marble_mesh = MaterialMesh2dBundle {
-        mesh: meshes
-            .add(shape::Circle::new(marble_radius as f32).into())
-            .into(),
-        material: materials.add(ColorMaterial::from(Color::rgb(0.2, 0.7, 0.9))),
-        ..default()
+    let log_plugin = LogPlugin {
+        filter:
+            // "info,wgpu_core=warn,wgpu_hal=warn,matchbox_socket=debug,bevy_ggrs=debug"
+                "info,wgpu_core=warn,wgpu_hal=warn,matchbox_socket=debug"
+                .into(),
+        level: bevy::log::Level::DEBUG,
     };
 
-    let half_width = 5;
-    let half_height = 5;
-
-    // Spawn stacks of marbles
-    for x in -half_width..=half_width {
-        for y in -half_height..=half_height {
-            let position = Vector::new(
-                x as Scalar * (2.5 * marble_radius),
-                y as Scalar * (2.5 * marble_radius),
-            );
-            commands
-                .spawn((
-                    marble_mesh.clone(),
-                    RigidBody::Dynamic,
-                    Position(position),
-                    Rotation::default(),
-                    Collider::ball(marble_radius),
-                    Friction::new(0.0),
-                    PrevPos(position),
-                    Marble,
-                ))
-                .add_rollback();
-        }
-    }
-}
+    let gaff_plugin = GaffPlugin::from_args(&args);
 
-fn movement(
-    inputs: Res<PlayerInputs<GgrsConfig>>,
-    mut marbles: Query<&mut LinearVelocity, With<Marble>>,
-) {
-    for input in inputs.iter() {
-        let buttons = input.0.buttons;
-        for mut linear_velocity in &mut marbles {
-            if buttons & INPUT_UP != 0 {
-                linear_velocity.y += 50.0;
-            }
-            if buttons & INPUT_DOWN != 0 {
-                linear_velocity.y -= 10.0;
-            }
-            if buttons & INPUT_LEFT != 0 {
-                linear_velocity.x -= 10.0;
-            }
-            if buttons & INPUT_RIGHT != 0 {
-                linear_velocity.x += 10.0;
-            }
-        }
-    }
-}
-
-#[derive(Debug, Clone, Default, Eq, PartialEq, Hash, States)]
-pub enum AppState {
-    #[default]
-    Startup,
-    Lobby,
-    InGame,
-    Paused,
-}
-
-#[derive(ScheduleLabel, Clone, Debug, Hash, Eq, PartialEq)]
-struct PhysicsSchedule;
+    let mut app = App::new();
 
-fn main() {
-    // read query string or command line arguments
-    let args = Args::get();
-    info!("{args:?}");
-
-    App::new()
-        .add_plugins((
-            DefaultPlugins
-                .set(LogPlugin {
-                    filter:
-                        // "info,wgpu_core=warn,wgpu_hal=warn,matchbox_socket=debug,bevy_ggrs=debug"
-                            "info,wgpu_core=warn,wgpu_hal=warn,matchbox_socket=debug"
-                            .into(),
-                    level: bevy::log::Level::DEBUG,
-                })
-                .set(WindowPlugin {
-                    primary_window: Some(Window {
-                        fit_canvas_to_parent: true, // behave on wasm
-                        ..default()
-                    }),
+    if args.headless {
+        app.add_plugins((
+            HeadlessPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(
+                1.0 / args.fps as f64,
+            ))),
+            log_plugin,
+            gaff_plugin,
+        ));
+    } else {
+        app.add_plugins((
+            DefaultPlugins.set(log_plugin).set(WindowPlugin {
+                primary_window: Some(Window {
+                    fit_canvas_to_parent: true, // behave on wasm
                     ..default()
                 }),
-            PhysicsPlugins::new(PhysicsSchedule),
+                ..default()
+            }),
             FrameTimeDiagnosticsPlugin,
-            LobbyPlugin,
-            GrabberPlugin,
+            gaff_plugin,
             WorldInspectorPlugin::default(),
         ))
-        .add_plugins(GgrsPlugin::<GgrsConfig>::default())
-        .add_systems(ReadInputs, input)
-        .add_plugins(GgrsComponentSnapshotClonePlugin::<Transform>::default())
-        .add_plugins(GgrsComponentSnapshotClonePlugin::<Position>::default())
-        .add_plugins(GgrsComponentSnapshotClonePlugin::<PreviousPosition>::default())
-        .add_plugins(GgrsComponentSnapshotClonePlugin::<LinearVelocity>::default())
-        .add_plugins(GgrsComponentSnapshotClonePlugin::<Rotation>::default())
-        .add_plugins(GgrsComponentSnapshotClonePlugin::<PreviousRotation>::default())
-        .add_plugins(GgrsComponentSnapshotClonePlugin::<AngularVelocity>::default())
-        .add_plugins(GgrsComponentSnapshotClonePlugin::<DistanceJoint>::default())
-        .add_plugins(GgrsComponentMapEntitiesPlugin::<DistanceJoint>::default())
-        .add_plugins(GgrsComponentSnapshotClonePlugin::<PrevPos>::default()) // just for desync detection
-        .add_plugins(GgrsComponentChecksumHashPlugin::<PrevPos>::default())
-        .add_plugins(GgrsResourceSnapshotClonePlugin::<FrameCount>::default())
-        .insert_resource(ClearColor(Color::rgb(0.05, 0.05, 0.1)))
-        .insert_resource(SubstepCount(6))
-        .insert_resource(Gravity(Vector::NEG_Y * 1000.0))
-        .insert_resource(PhysicsTimestep::FixedOnce(1. / FPS as f32))
-        .init_resource::<FrameCount>()
-        // Some of our systems need the query parameters
-        .insert_resource(args)
-        .add_state::<AppState>()
-        .add_systems(Startup, (setup, setup_scene, spawn_marbles).chain())
-        .add_systems(Update, log_ggrs_events.run_if(in_state(AppState::InGame)))
-        // these systems will be executed as part of the advance frame update
-        .add_systems(
-            GgrsSchedule,
-            (
-                // ideally these systems should be part of the rollback schedule, but seems it breaks
-                // synctest sessions for some reason... should investigate...
-                // setup_scene,
-                // spawn_marbles,
-                step_physics,
-                movement,
-                update_previous_position,
-                increase_frame_system,
-            )
-                .chain(),
-        )
-        .add_systems(GgrsSchedule, grabber_2d::grab.before(step_physics))
-        .run();
-}
-
-fn setup(mut commands: Commands, mut app_state: ResMut<NextState<AppState>>, args: Res<Args>) {
-    commands.spawn((MainCamera, Camera2dBundle::default()));
-    if args.players == 1 {
-        info!("starting synctest session");
-        let mut session_builder = configure_session(1);
-        session_builder = session_builder
-            .add_player(PlayerType::Local, 0)
-            .expect("failed to add player");
-        let session = session_builder
-            .start_synctest_session()
-            .expect("failed to start synctest session");
-        commands.insert_resource(Session::SyncTest(session));
-        app_state.set(AppState::InGame)
-    } else {
-        info!("joining multiplayer lobby");
-        app_state.set(AppState::Lobby)
+        .insert_resource(ClearColor(Color::rgb(0.05, 0.05, 0.1)));
     }
-}
 
-pub fn configure_session(players: usize) -> SessionBuilder<GgrsConfig> {
-    SessionBuilder::<GgrsConfig>::new()
-        .with_num_players(players)
-        .with_max_prediction_window(12)
-        // TODO: re-enable input delay when rollbacks are working properly
-        // .with_input_delay(2)
-        .with_input_delay(0)
-        .with_fps(FPS)
-        .expect("invalid fps")
+    app.insert_resource(args).run();
 }
 
-fn log_ggrs_events(mut session: ResMut<Session<GgrsConfig>>) {
-    match session.as_mut() {
-        Session::P2P(s) => {
-            for event in s.events() {
-                info!("GGRS Event: {event:?}");
-                if let GgrsEvent::DesyncDetected { .. } = event {
-                    panic!("desynced!");
+fn run_command(command: &Command) {
+    match command {
+        Command::DiffDumps { a, b } => {
+            let read = |path: &Path| {
+                std::fs::read_to_string(path).unwrap_or_else(|e| {
+                    eprintln!("error: can't read {}: {e}", path.display());
+                    std::process::exit(2);
+                })
+            };
+            match diff_dumps(&read(a), &read(b)) {
+                Some(divergence) => {
+                    println!("{divergence}");
+                    std::process::exit(1);
                 }
+                None => println!("dumps are identical"),
             }
         }
-        Session::SyncTest(_) => {}
-        _ => panic!("This example focuses on p2p and synctest"),
-    }
-}
-
-fn increase_frame_system(mut frame_count: ResMut<FrameCount>) {
-    frame_count.frame += 1;
-}
-
-fn update_previous_position(mut positions: Query<(&mut PrevPos, &Position)>) {
-    for (mut previous_position, position) in &mut positions {
-        previous_position.0 = position.0;
     }
 }
-
-fn step_physics(world: &mut World) {
-    world.run_schedule(PhysicsSchedule);
-}
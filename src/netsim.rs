@@ -0,0 +1,341 @@
+//! Simulated network conditions for local testing
+//!
+//! [`SimulatedSocket`] wraps the socket GGRS sends its packets through, and
+//! adds latency, jitter, loss, duplication and reordering to outgoing packets,
+//! so mispredictions and deep rollbacks can be provoked on a LAN.
+
+use bevy::utils::{HashMap, Instant};
+use bevy_ggrs::ggrs::{Message, NonBlockingSocket};
+use serde::{de::Error, Deserialize, Deserializer};
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hash, Hasher},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::args::Args;
+
+/// A duration given as `120ms`, `1.5s` or a plain number of milliseconds
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SimDuration(pub Duration);
+
+impl FromStr for SimDuration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (number, seconds_per_unit) = if let Some(ms) = s.strip_suffix("ms") {
+            (ms, 0.001)
+        } else if let Some(secs) = s.strip_suffix('s') {
+            (secs, 1.0)
+        } else {
+            (s, 0.001)
+        };
+        let value: f64 = number
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid duration {s:?}, expected e.g. 120ms"))?;
+        if !value.is_finite() || value < 0.0 {
+            return Err(format!("invalid duration {s:?}, must not be negative"));
+        }
+        Ok(Self(Duration::from_secs_f64(value * seconds_per_unit)))
+    }
+}
+
+impl<'de> Deserialize<'de> for SimDuration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+/// A probability given as a percentage, e.g. `5%` or `5`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Percent(pub f32);
+
+impl Percent {
+    /// The probability between 0 and 1
+    pub fn probability(self) -> f32 {
+        self.0 / 100.0
+    }
+}
+
+impl FromStr for Percent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let value: f32 = s
+            .strip_suffix('%')
+            .unwrap_or(s)
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid percentage {s:?}, expected e.g. 5%"))?;
+        if !(0.0..=100.0).contains(&value) {
+            return Err(format!(
+                "invalid percentage {s:?}, must be between 0 and 100"
+            ));
+        }
+        Ok(Self(value))
+    }
+}
+
+impl<'de> Deserialize<'de> for Percent {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+/// Conditions applied to every outgoing packet
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NetworkConditions {
+    /// Added to every packet
+    pub latency: Duration,
+    /// Each packet is delayed by up to this much more or less than `latency`
+    pub jitter: Duration,
+    /// Probability that a packet is dropped
+    pub loss: f32,
+    /// Probability that a packet is sent twice
+    pub duplication: f32,
+    /// Probability that a packet is held back until after the next packet to
+    /// the same peer
+    pub reordering: f32,
+}
+
+impl NetworkConditions {
+    pub fn from_args(args: &Args) -> Self {
+        Self {
+            latency: args.sim_latency.0,
+            jitter: args.sim_jitter.0,
+            loss: args.sim_loss.probability(),
+            duplication: args.sim_duplication.probability(),
+            reordering: args.sim_reordering.probability(),
+        }
+    }
+
+    /// Whether packets are passed through untouched
+    pub fn is_perfect(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Wraps a socket, applying [`NetworkConditions`] to outgoing packets
+pub struct SimulatedSocket<S, A> {
+    inner: S,
+    conditions: NetworkConditions,
+    rng: SplitMix64,
+    /// Packets waiting for their delay to pass, with the time they are due
+    delayed: Vec<(Instant, A, Message)>,
+    /// Packets held back to be sent after the next packet to the same peer
+    held_back: HashMap<A, Message>,
+}
+
+impl<S, A> SimulatedSocket<S, A> {
+    pub fn new(inner: S, conditions: NetworkConditions) -> Self {
+        let seed = RandomState::new().build_hasher().finish();
+        Self::with_seed(inner, conditions, seed)
+    }
+
+    /// Use a fixed seed, for reproducible conditions
+    pub fn with_seed(inner: S, conditions: NetworkConditions, seed: u64) -> Self {
+        Self {
+            inner,
+            conditions,
+            rng: SplitMix64(seed),
+            delayed: Vec::new(),
+            held_back: HashMap::default(),
+        }
+    }
+}
+
+impl<S, A> SimulatedSocket<S, A>
+where
+    S: NonBlockingSocket<A>,
+    A: Clone + PartialEq + Eq + Hash + Send + Sync,
+{
+    fn delay(&mut self) -> Duration {
+        let NetworkConditions {
+            latency, jitter, ..
+        } = self.conditions;
+        let offset = jitter.mul_f32(self.rng.next_f32() * 2.0);
+        (latency + offset).saturating_sub(jitter)
+    }
+
+    fn enqueue(&mut self, msg: Message, addr: A) {
+        let due = Instant::now() + self.delay();
+        self.delayed.push((due, addr, msg));
+    }
+
+    /// Sends the packets whose delay has passed, in the order they are due
+    fn flush(&mut self) {
+        let now = Instant::now();
+        self.delayed.sort_by_key(|(due, _, _)| *due);
+        let due = self.delayed.iter().take_while(|(due, _, _)| *due <= now);
+        let count = due.count();
+        for (_, addr, msg) in self.delayed.drain(..count) {
+            self.inner.send_to(&msg, &addr);
+        }
+    }
+}
+
+impl<S, A> NonBlockingSocket<A> for SimulatedSocket<S, A>
+where
+    S: NonBlockingSocket<A>,
+    A: Clone + PartialEq + Eq + Hash + Send + Sync,
+{
+    fn send_to(&mut self, msg: &Message, addr: &A) {
+        if self.conditions.is_perfect() {
+            self.inner.send_to(msg, addr);
+            return;
+        }
+
+        if self.rng.next_f32() >= self.conditions.loss {
+            if self.rng.next_f32() < self.conditions.reordering {
+                // if a packet was already held back, it goes out now instead
+                if let Some(previous) = self.held_back.insert(addr.clone(), msg.clone()) {
+                    self.enqueue(previous, addr.clone());
+                }
+            } else {
+                self.enqueue(msg.clone(), addr.clone());
+                if let Some(held_back) = self.held_back.remove(addr) {
+                    self.enqueue(held_back, addr.clone());
+                }
+            }
+
+            if self.rng.next_f32() < self.conditions.duplication {
+                self.enqueue(msg.clone(), addr.clone());
+            }
+        }
+
+        self.flush();
+    }
+
+    fn receive_all_messages(&mut self) -> Vec<(A, Message)> {
+        self.flush();
+        self.inner.receive_all_messages()
+    }
+}
+
+/// A tiny deterministic rng, so we don't need a dependency for this
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniformly distributed in `[0, 1)`
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::prelude::default;
+
+    #[test]
+    fn parses_durations() {
+        let ms = |s: &str| s.parse::<SimDuration>().unwrap().0.as_secs_f64() * 1000.0;
+        assert!((ms("120ms") - 120.0).abs() < 1e-6);
+        assert!((ms(" 1.5s ") - 1500.0).abs() < 1e-6);
+        assert!((ms("80") - 80.0).abs() < 1e-6);
+        assert!("-5ms".parse::<SimDuration>().is_err());
+        assert!("fast".parse::<SimDuration>().is_err());
+        assert!("inf".parse::<SimDuration>().is_err());
+    }
+
+    #[test]
+    fn parses_percentages() {
+        assert_eq!("5%".parse(), Ok(Percent(5.0)));
+        assert_eq!("12.5".parse(), Ok(Percent(12.5)));
+        assert_eq!(Percent(50.0).probability(), 0.5);
+        assert!("101%".parse::<Percent>().is_err());
+        assert!("-1".parse::<Percent>().is_err());
+        assert!("lots".parse::<Percent>().is_err());
+    }
+
+    /// Counts the packets that make it through
+    #[derive(Default)]
+    struct CountingSocket(usize);
+
+    impl NonBlockingSocket<u32> for CountingSocket {
+        fn send_to(&mut self, _msg: &Message, _addr: &u32) {
+            self.0 += 1;
+        }
+
+        fn receive_all_messages(&mut self) -> Vec<(u32, Message)> {
+            Vec::new()
+        }
+    }
+
+    fn message() -> Message {
+        // ggrs doesn't expose a constructor, all zeros decode to its first
+        // message type
+        bincode::deserialize(&[0; 64]).unwrap()
+    }
+
+    /// Sends `count` packets through a seeded socket, returns how many arrive
+    fn delivered(conditions: NetworkConditions, count: usize) -> usize {
+        let mut socket = SimulatedSocket::with_seed(CountingSocket::default(), conditions, 7);
+        let msg = message();
+        for i in 0..count {
+            socket.send_to(&msg, &(i as u32 % 2));
+        }
+        socket.inner.0
+    }
+
+    #[test]
+    fn perfect_conditions_deliver_everything() {
+        assert_eq!(delivered(default(), 1000), 1000);
+    }
+
+    #[test]
+    fn drops_packets_at_the_loss_rate() {
+        let conditions = NetworkConditions {
+            loss: 0.25,
+            ..default()
+        };
+        let delivered = delivered(conditions, 10_000);
+        assert!(
+            (7_300..=7_700).contains(&delivered),
+            "{delivered} delivered"
+        );
+    }
+
+    #[test]
+    fn duplicates_packets_at_the_duplication_rate() {
+        let conditions = NetworkConditions {
+            duplication: 0.1,
+            ..default()
+        };
+        let delivered = delivered(conditions, 10_000);
+        assert!(
+            (10_800..=11_200).contains(&delivered),
+            "{delivered} delivered"
+        );
+    }
+
+    #[test]
+    fn reordering_holds_packets_back_without_losing_them() {
+        let conditions = NetworkConditions {
+            reordering: 0.5,
+            ..default()
+        };
+        // at most one packet per peer is still held back
+        let delivered = delivered(conditions, 10_000);
+        assert!(
+            (9_998..=10_000).contains(&delivered),
+            "{delivered} delivered"
+        );
+    }
+}
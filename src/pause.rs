@@ -0,0 +1,238 @@
+//! Synchronized pausing
+//!
+//! Players pause by holding [`INPUT_PAUSE`] in their input, so pausing goes
+//! through GGRS like any other input, and the simulation stops on the same
+//! frame for every peer. The match stays paused as long as any player holds
+//! the bit. When the match pauses on a confirmed frame, every player starts
+//! holding it, so it only resumes once all players have agreed to.
+
+use bevy::prelude::*;
+use bevy_ggrs::{PlayerInputs, Session};
+use std::{
+    collections::BTreeMap,
+    hash::{Hash, Hasher},
+};
+
+use crate::{
+    chat::ChatFocus,
+    checksum::{BitHash, RollbackIds},
+    input::INPUT_PAUSE,
+    AppState, FrameCount, GgrsConfig, SessionFrameOffset,
+};
+
+pub struct PausePlugin;
+
+impl Plugin for PausePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PauseState>()
+            .init_resource::<PauseRequest>()
+            .add_systems(
+                Update,
+                (pause_keyboard, sync_app_state).run_if(crate::in_match),
+            )
+            .add_systems(OnEnter(AppState::Paused), spawn_pause_overlay)
+            .add_systems(
+                Update,
+                update_pause_overlay.run_if(in_state(AppState::Paused)),
+            )
+            .add_systems(OnExit(AppState::Paused), despawn_pause_overlay);
+    }
+}
+
+/// Whether the simulation is paused, part of the rollback state
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PauseState {
+    pub paused: bool,
+    /// The number of players holding the pause input
+    pub holding: usize,
+}
+
+impl BitHash for PauseState {
+    fn bit_hash<H: Hasher>(&self, _ids: &RollbackIds, state: &mut H) {
+        self.hash(state);
+    }
+}
+
+/// Whether the local player wants the match paused, sent as [`INPUT_PAUSE`]
+#[derive(Resource, Default, Debug)]
+pub struct PauseRequest {
+    pub requested: bool,
+    was_paused: bool,
+    /// Whether each simulated frame was paused, frames that aren't confirmed
+    /// yet may be resimulated differently
+    paused_frames: BTreeMap<usize, bool>,
+}
+
+impl PauseRequest {
+    /// Whether the latest confirmed frame was paused, forgets older frames
+    fn confirmed_pause(&mut self, confirmed_frame: Option<usize>) -> bool {
+        let Some(confirmed_frame) = confirmed_frame else {
+            return false;
+        };
+        let Some((&frame, &paused)) = self.paused_frames.range(..=confirmed_frame).next_back()
+        else {
+            return false;
+        };
+        self.paused_frames = self.paused_frames.split_off(&frame);
+        paused
+    }
+}
+
+/// Pauses and resumes the simulation, runs first in the rollback schedule
+pub fn update_pause(
+    inputs: Res<PlayerInputs<GgrsConfig>>,
+    frame: Res<FrameCount>,
+    mut pause: ResMut<PauseState>,
+    mut request: ResMut<PauseRequest>,
+) {
+    // disconnected players have blank input, so they never hold up the match
+    let holding = inputs
+        .iter()
+        .filter(|(input, _status)| input.buttons & INPUT_PAUSE != 0)
+        .count();
+    let paused = holding > 0;
+    if paused != pause.paused {
+        info!("{}", if paused { "paused" } else { "resumed" });
+    }
+    *pause = PauseState { paused, holding };
+    // resimulated frames replace the predicted ones
+    request.paused_frames.insert(frame.frame, paused);
+}
+
+/// Run condition for systems that advance the simulation
+pub fn simulation_running(pause: Res<PauseState>) -> bool {
+    !pause.paused
+}
+
+fn pause_keyboard(
+    keyboard: Option<Res<Input<KeyCode>>>,
+    session: Option<Res<Session<GgrsConfig>>>,
+    offset: Res<SessionFrameOffset>,
+    mut request: ResMut<PauseRequest>,
+    chat_focus: Res<ChatFocus>,
+) {
+    // paused_frames are keyed by FrameCount, which includes the offset
+    let confirmed_frame = match session.as_deref() {
+        Some(Session::P2P(s)) => usize::try_from(s.confirmed_frame()).ok(),
+        // all inputs are local, so everything simulated is confirmed
+        Some(Session::SyncTest(_) | Session::Spectator(_)) | None => Some(usize::MAX),
+    }
+    .map(|frame| frame.saturating_add(offset.0));
+
+    // Everyone has to agree before the match resumes. A mispredicted pause
+    // may be rolled back, so only confirmed pauses are latched.
+    let paused = request.confirmed_pause(confirmed_frame);
+    if paused && !request.was_paused {
+        request.requested = true;
+    }
+    request.was_paused = paused;
+
+    if !chat_focus.0 && keyboard.is_some_and(|keyboard| keyboard.just_pressed(KeyCode::P)) {
+        request.requested = !request.requested;
+    }
+}
+
+/// Mirrors the simulation's pause into [`AppState::Paused`]
+fn sync_app_state(
+    pause: Res<PauseState>,
+    state: Res<State<AppState>>,
+    mut next_state: ResMut<NextState<AppState>>,
+) {
+    match (state.get(), pause.paused) {
+        (AppState::InGame, true) => next_state.set(AppState::Paused),
+        (AppState::Paused, false) => next_state.set(AppState::InGame),
+        _ => {}
+    }
+}
+
+#[derive(Component)]
+struct PauseOverlay;
+
+#[derive(Component)]
+struct PauseText;
+
+fn spawn_pause_overlay(mut commands: Commands, asset_server: Option<Res<AssetServer>>) {
+    // No UI when running headless
+    let Some(asset_server) = asset_server else {
+        return;
+    };
+
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    position_type: PositionType::Absolute,
+                    justify_content: JustifyContent::Center,
+                    align_items: AlignItems::Center,
+                    ..default()
+                },
+                background_color: Color::rgba(0.0, 0.0, 0.0, 0.5).into(),
+                ..default()
+            },
+            PauseOverlay,
+        ))
+        .with_children(|parent| {
+            parent.spawn((
+                TextBundle::from_section(
+                    "Paused",
+                    TextStyle {
+                        font: asset_server.load("fonts/quicksand-light.ttf"),
+                        font_size: 48.,
+                        color: Color::WHITE,
+                    },
+                )
+                .with_text_alignment(TextAlignment::Center),
+                PauseText,
+            ));
+        });
+}
+
+fn update_pause_overlay(
+    pause: Res<PauseState>,
+    request: Res<PauseRequest>,
+    mut texts: Query<&mut Text, With<PauseText>>,
+) {
+    let hint = if request.requested {
+        "press P to resume"
+    } else {
+        "waiting for the others to resume"
+    };
+    for mut text in &mut texts {
+        text.sections[0].value = format!(
+            "Paused\n{} player(s) holding the pause\n{hint}",
+            pause.holding
+        );
+    }
+}
+
+fn despawn_pause_overlay(mut commands: Commands, overlays: Query<Entity, With<PauseOverlay>>) {
+    for entity in &overlays {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_confirmed_pauses_count() {
+        let mut request = PauseRequest::default();
+        for (frame, paused) in [(10, false), (11, true), (12, true)] {
+            request.paused_frames.insert(frame, paused);
+        }
+        assert!(!request.confirmed_pause(None));
+        assert!(!request.confirmed_pause(Some(10)));
+
+        // a rollback un-pauses the predicted frames
+        request.paused_frames.insert(11, false);
+        request.paused_frames.insert(12, false);
+        assert!(!request.confirmed_pause(Some(12)));
+
+        request.paused_frames.insert(13, true);
+        assert!(request.confirmed_pause(Some(20)));
+        assert_eq!(request.paused_frames.len(), 1);
+    }
+}
@@ -0,0 +1,570 @@
+//! Recording and playback of replay files
+//!
+//! A replay file starts with a header containing the session configuration,
+//! followed by a record for every confirmed frame's inputs, and a checksum of
+//! the simulation state every [`CHECKSUM_INTERVAL`] frames. All values are
+//! little endian.
+//!
+//! Replays are played back without network, by feeding the recorded inputs to
+//! a local SyncTest session, and the recorded checksums are compared against
+//! the live state.
+
+use bevy::{app::AppExit, prelude::*, utils::HashMap};
+use bevy_ggrs::{
+    ggrs::{PlayerType, SessionBuilder},
+    GgrsSchedule, LocalInputs, LocalPlayers, PlayerInputs, ReadInputs, Session,
+};
+use bevy_xpbd_2d::prelude::*;
+use std::{
+    collections::BTreeMap,
+    io::{self, Read, Write},
+};
+
+use crate::{
+    args::Args,
+    configure_session,
+    desync::{Desynced, FrameDumps},
+    grabber_2d::{GrabberSettings, InitialGrabberSettings},
+    input::GaffInput,
+    viewer::ReplayViewer,
+    AppState, FrameCount, GgrsConfig, SessionConfig, SessionFrameOffset,
+};
+
+pub const REPLAY_MAGIC: &[u8; 4] = b"GAFF";
+pub const REPLAY_VERSION: u32 = 4;
+
+/// How often to record checksums, in frames
+pub const CHECKSUM_INTERVAL: usize = 60;
+
+const RECORD_INPUTS: u8 = 0;
+const RECORD_CHECKSUM: u8 = 1;
+
+pub struct RecordPlugin;
+
+impl Plugin for RecordPlugin {
+    fn build(&self, app: &mut App) {
+        // resuming from a pause enters InGame again
+        app.add_systems(
+            OnEnter(AppState::InGame),
+            start_recording
+                .run_if(not(resource_exists::<Recorder>()))
+                .run_if(not(resource_exists::<RecordingFailed>())),
+        )
+        .add_systems(
+            GgrsSchedule,
+            record_frame_inputs
+                .before(crate::increase_frame_system)
+                .run_if(resource_exists::<Recorder>()),
+        )
+        .add_systems(
+            Update,
+            write_confirmed_frames
+                .run_if(resource_exists::<Recorder>())
+                .run_if(resource_exists::<Session<GgrsConfig>>()),
+        );
+    }
+}
+
+pub struct PlaybackPlugin;
+
+impl Plugin for PlaybackPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(
+            ReadInputs,
+            playback_inputs.run_if(resource_exists::<Playback>()),
+        )
+        .add_systems(
+            Update,
+            verify_playback
+                .run_if(resource_exists::<Playback>())
+                .run_if(crate::in_match),
+        );
+    }
+}
+
+/// The session configuration a replay was recorded with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReplayHeader {
+    pub players: u32,
+    pub fps: u32,
+    pub substep_count: u32,
+    pub gravity: Vec2,
+    /// The grabber settings the match started with
+    pub grabber: GrabberSettings,
+}
+
+/// A decoded replay file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Replay {
+    pub header: ReplayHeader,
+    /// All players' inputs, by frame
+    pub inputs: Vec<Vec<GaffInput>>,
+    /// Checksums of the state at the start of the frame, by frame
+    pub checksums: BTreeMap<usize, u64>,
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_f32(reader: &mut impl Read) -> io::Result<f32> {
+    read_u32(reader).map(f32::from_bits)
+}
+
+impl Replay {
+    pub fn read(reader: &mut impl Read) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != REPLAY_MAGIC {
+            return Err(invalid_data("not a bevy_gaff replay"));
+        }
+        let version = read_u32(reader)?;
+        if version != REPLAY_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported replay version {version}, expected {REPLAY_VERSION}"
+            )));
+        }
+
+        let header = ReplayHeader {
+            players: read_u32(reader)?,
+            fps: read_u32(reader)?,
+            substep_count: read_u32(reader)?,
+            gravity: Vec2::new(read_f32(reader)?, read_f32(reader)?),
+            grabber: GrabberSettings {
+                max_distance: read_f32(reader)?,
+                compliance: read_f32(reader)?,
+                linear_damping: read_f32(reader)?,
+                angular_damping: read_f32(reader)?,
+            },
+        };
+
+        let mut inputs = Vec::new();
+        let mut checksums = BTreeMap::new();
+
+        loop {
+            let mut tag = [0];
+            match reader.read_exact(&mut tag) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+            let frame = read_u32(reader)? as usize;
+            match tag[0] {
+                RECORD_INPUTS => {
+                    if frame != inputs.len() {
+                        return Err(invalid_data(format!(
+                            "expected inputs for frame {}, got {frame}",
+                            inputs.len()
+                        )));
+                    }
+                    let mut frame_inputs = vec![GaffInput::default(); header.players as usize];
+                    reader.read_exact(bytemuck::cast_slice_mut(&mut frame_inputs))?;
+                    inputs.push(frame_inputs);
+                }
+                RECORD_CHECKSUM => {
+                    let mut bytes = [0; 8];
+                    reader.read_exact(&mut bytes)?;
+                    checksums.insert(frame, u64::from_le_bytes(bytes));
+                }
+                tag => return Err(invalid_data(format!("unknown record {tag}"))),
+            }
+        }
+
+        Ok(Self {
+            header,
+            inputs,
+            checksums,
+        })
+    }
+}
+
+/// Writes replay files, record by record
+pub struct ReplayWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> ReplayWriter<W> {
+    pub fn new(mut writer: W, header: ReplayHeader) -> io::Result<Self> {
+        writer.write_all(REPLAY_MAGIC)?;
+        writer.write_all(&REPLAY_VERSION.to_le_bytes())?;
+        writer.write_all(&header.players.to_le_bytes())?;
+        writer.write_all(&header.fps.to_le_bytes())?;
+        writer.write_all(&header.substep_count.to_le_bytes())?;
+        writer.write_all(&header.gravity.x.to_le_bytes())?;
+        writer.write_all(&header.gravity.y.to_le_bytes())?;
+        writer.write_all(&header.grabber.max_distance.to_le_bytes())?;
+        writer.write_all(&header.grabber.compliance.to_le_bytes())?;
+        writer.write_all(&header.grabber.linear_damping.to_le_bytes())?;
+        writer.write_all(&header.grabber.angular_damping.to_le_bytes())?;
+        Ok(Self { writer })
+    }
+
+    pub fn write_inputs(&mut self, frame: usize, inputs: &[GaffInput]) -> io::Result<()> {
+        self.writer.write_all(&[RECORD_INPUTS])?;
+        self.writer.write_all(&(frame as u32).to_le_bytes())?;
+        self.writer.write_all(bytemuck::cast_slice(inputs))
+    }
+
+    pub fn write_checksum(&mut self, frame: usize, checksum: u64) -> io::Result<()> {
+        self.writer.write_all(&[RECORD_CHECKSUM])?;
+        self.writer.write_all(&(frame as u32).to_le_bytes())?;
+        self.writer.write_all(&checksum.to_le_bytes())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Records the current match, removed when the match ends
+#[derive(Resource)]
+pub(crate) struct Recorder {
+    writer: ReplayWriter<Box<dyn Write + Send + Sync>>,
+    /// Inputs of frames that may still be resimulated
+    pending: BTreeMap<usize, Vec<GaffInput>>,
+}
+
+impl Recorder {
+    /// Writes out the pending frames up to and including `confirmed_frame`
+    fn write_confirmed(
+        &mut self,
+        confirmed_frame: i32,
+        frame_dumps: &FrameDumps,
+    ) -> io::Result<()> {
+        while let Some(entry) = self.pending.first_entry() {
+            let frame = *entry.key();
+            if frame as i32 > confirmed_frame {
+                break;
+            }
+            let inputs = entry.remove();
+            self.writer.write_inputs(frame, &inputs)?;
+
+            // the state after this frame's inputs have been applied
+            let next_frame = frame + 1;
+            if next_frame % CHECKSUM_INTERVAL == 0 {
+                if let Some(dump) = frame_dumps.get(next_frame) {
+                    self.writer.write_checksum(next_frame, dump.checksum())?;
+                }
+            }
+        }
+        self.writer.flush()
+    }
+
+    /// Writes out the confirmed frames, the unconfirmed ones are dropped
+    pub(crate) fn finish(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            error!("failed to write replay: {e}");
+        }
+    }
+}
+
+/// Inserted when recording the current match failed, so it isn't restarted
+/// halfway through when resuming from a pause
+#[derive(Resource)]
+pub(crate) struct RecordingFailed;
+
+/// Every match is recorded to its own file, `path` for the first one and
+/// `<stem>-<n>.<extension>` for the following ones
+fn match_path(path: &std::path::Path, index: usize) -> std::path::PathBuf {
+    if index == 0 {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut file_name = format!("{stem}-{}", index + 1);
+    if let Some(extension) = path.extension() {
+        file_name += &format!(".{}", extension.to_string_lossy());
+    }
+    path.with_file_name(file_name)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn create_replay_file(path: &std::path::Path) -> io::Result<Box<dyn Write + Send + Sync>> {
+    let file = std::fs::File::create(path)?;
+    Ok(Box::new(io::BufWriter::new(file)))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn create_replay_file(_path: &std::path::Path) -> io::Result<Box<dyn Write + Send + Sync>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "no file system on wasm",
+    ))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn start_recording(
+    mut commands: Commands,
+    mut matches: Local<usize>,
+    args: Res<Args>,
+    offset: Res<SessionFrameOffset>,
+    session: Res<Session<GgrsConfig>>,
+    session_config: Res<SessionConfig>,
+    substep_count: Res<SubstepCount>,
+    gravity: Res<Gravity>,
+    grabber: Res<GrabberSettings>,
+) {
+    let Some(path) = &args.record else {
+        return;
+    };
+    // replays start from the beginning of the match, not a snapshot
+    if offset.0 != 0 {
+        error!("not recording, the match was joined on frame {}", offset.0);
+        return;
+    }
+    let path = &match_path(path, *matches);
+    *matches += 1;
+
+    let players = match session.as_ref() {
+        Session::P2P(s) => s.num_players(),
+        Session::SyncTest(s) => s.num_players(),
+        Session::Spectator(s) => s.num_players(),
+    };
+
+    let header = ReplayHeader {
+        players: players as u32,
+        fps: session_config.fps as u32,
+        substep_count: substep_count.0,
+        gravity: gravity.0,
+        grabber: *grabber,
+    };
+
+    let writer = match create_replay_file(path) {
+        Ok(writer) => writer,
+        Err(e) => {
+            error!("failed to create replay file {path:?}: {e}");
+            commands.insert_resource(RecordingFailed);
+            return;
+        }
+    };
+    let writer = match ReplayWriter::new(writer, header) {
+        Ok(writer) => writer,
+        Err(e) => {
+            stop_recording(&mut commands, e);
+            return;
+        }
+    };
+
+    info!("recording replay to {path:?}");
+    commands.insert_resource(Recorder {
+        writer,
+        pending: default(),
+    });
+}
+
+fn record_frame_inputs(
+    frame: Res<FrameCount>,
+    inputs: Res<PlayerInputs<GgrsConfig>>,
+    mut recorder: ResMut<Recorder>,
+) {
+    // resimulated frames replace the predicted inputs
+    let inputs = inputs.iter().map(|(input, _status)| *input).collect();
+    recorder.pending.insert(frame.frame, inputs);
+}
+
+/// Stops recording the rest of the match, the match itself keeps running
+fn stop_recording(commands: &mut Commands, error: io::Error) {
+    error!("failed to write replay, stopped recording: {error}");
+    commands.remove_resource::<Recorder>();
+    commands.insert_resource(RecordingFailed);
+}
+
+fn write_confirmed_frames(
+    mut commands: Commands,
+    mut recorder: ResMut<Recorder>,
+    session: Res<Session<GgrsConfig>>,
+    offset: Res<SessionFrameOffset>,
+    frame_dumps: Res<FrameDumps>,
+) {
+    // pending inputs are keyed by FrameCount, which includes the offset
+    let confirmed_frame = match session.as_ref() {
+        Session::P2P(s) => s.confirmed_frame().saturating_add(offset.0 as i32),
+        // all inputs are local, so everything simulated is confirmed
+        Session::SyncTest(_) | Session::Spectator(_) => i32::MAX,
+    };
+
+    if let Err(e) = recorder.write_confirmed(confirmed_frame, &frame_dumps) {
+        stop_recording(&mut commands, e);
+    }
+}
+
+/// A replay being played back
+#[derive(Resource)]
+pub struct Playback {
+    replay: Replay,
+    /// The next frame to read inputs for
+    next_frame: usize,
+    /// The next recorded checksum to verify
+    next_checksum: usize,
+    reported_finished: bool,
+}
+
+impl Playback {
+    pub fn new(replay: Replay) -> Self {
+        Self {
+            replay,
+            next_frame: 0,
+            next_checksum: 0,
+            reported_finished: false,
+        }
+    }
+
+    /// Continues playback from `frame`, after the world has been restored to it
+    pub fn rewind(&mut self, frame: usize) {
+        self.next_frame = frame;
+        self.next_checksum = frame;
+        self.reported_finished = false;
+    }
+
+    /// Starts a local session with all of the replay's players
+    pub fn session_builder(&self, session_config: &SessionConfig) -> SessionBuilder<GgrsConfig> {
+        let header = &self.replay.header;
+        let session_config = SessionConfig {
+            fps: header.fps as usize,
+            ..*session_config
+        };
+        let players = header.players as usize;
+        let mut session_builder = configure_session(players, &session_config)
+            // no rollbacks, we just want to simulate the recorded frames
+            .with_check_distance(0);
+        for handle in 0..players {
+            session_builder = session_builder
+                .add_player(PlayerType::Local, handle)
+                .expect("failed to add player");
+        }
+        session_builder
+    }
+
+    pub fn replay(&self) -> &Replay {
+        &self.replay
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next_frame >= self.replay.inputs.len()
+    }
+}
+
+/// Loads a replay, and sets up physics and a local session to play it back
+pub fn start_playback(
+    commands: &mut Commands,
+    path: &std::path::Path,
+    session_config: &SessionConfig,
+) -> io::Result<()> {
+    let replay = Replay::read(&mut io::BufReader::new(std::fs::File::open(path)?))?;
+    let header = replay.header;
+    info!(
+        "playing back {} frames from {path:?}, {header:?}",
+        replay.inputs.len()
+    );
+
+    let playback = Playback::new(replay);
+    let session = playback
+        .session_builder(session_config)
+        .start_synctest_session()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+
+    commands.insert_resource(SubstepCount(header.substep_count));
+    commands.insert_resource(Gravity(header.gravity));
+    commands.insert_resource(PhysicsTimestep::FixedOnce(1.0 / header.fps as f32));
+    commands.insert_resource(header.grabber);
+    commands.insert_resource(InitialGrabberSettings(header.grabber));
+    commands.insert_resource(Session::SyncTest(session));
+    commands.insert_resource(playback);
+    Ok(())
+}
+
+fn playback_inputs(
+    mut commands: Commands,
+    mut playback: ResMut<Playback>,
+    local_players: Res<LocalPlayers>,
+) {
+    // past the end of the replay, just send empty input until we stop
+    let frame_inputs = playback.replay.inputs.get(playback.next_frame);
+    let local_inputs: HashMap<_, _> = local_players
+        .0
+        .iter()
+        .map(|&handle| {
+            let input = frame_inputs
+                .and_then(|inputs| inputs.get(handle))
+                .copied()
+                .unwrap_or_default();
+            (handle, input)
+        })
+        .collect();
+    commands.insert_resource(LocalInputs::<GgrsConfig>(local_inputs));
+    playback.next_frame += 1;
+}
+
+fn verify_playback(
+    mut commands: Commands,
+    mut playback: ResMut<Playback>,
+    frame: Res<FrameCount>,
+    frame_dumps: Res<FrameDumps>,
+    args: Res<Args>,
+    viewer: Option<Res<ReplayViewer>>,
+    desynced: Option<Res<Desynced>>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let checksums: Vec<_> = playback
+        .replay
+        .checksums
+        .range(playback.next_checksum..=frame.frame)
+        .map(|(frame, checksum)| (*frame, *checksum))
+        .collect();
+
+    for (checksum_frame, recorded) in checksums {
+        playback.next_checksum = checksum_frame + 1;
+
+        let Some(live) = frame_dumps.get(checksum_frame).map(|dump| dump.checksum()) else {
+            warn!("no state for frame {checksum_frame}, skipping checksum");
+            continue;
+        };
+
+        if live != recorded {
+            error!(
+                "replay diverged on frame {checksum_frame}: \
+                 recorded checksum {recorded:016x}, live {live:016x}"
+            );
+            if args.headless {
+                std::process::exit(1);
+            }
+            if desynced.is_none() {
+                commands.insert_resource(Desynced {
+                    frame: checksum_frame,
+                    dump_path: None,
+                });
+            }
+            // keep going in the viewer, so the divergence can be inspected
+            if viewer.is_none() {
+                commands.remove_resource::<Session<GgrsConfig>>();
+                commands.remove_resource::<Playback>();
+            }
+            return;
+        }
+    }
+
+    if playback.is_finished()
+        && frame.frame >= playback.replay.inputs.len()
+        && !playback.reported_finished
+    {
+        info!(
+            "replay finished after {} frames, {} checksums matched",
+            playback.replay.inputs.len(),
+            playback.replay.checksums.len()
+        );
+        playback.reported_finished = true;
+        // the viewer may still seek backwards
+        if viewer.is_none() {
+            commands.remove_resource::<Session<GgrsConfig>>();
+            commands.remove_resource::<Playback>();
+        }
+        if args.headless {
+            exit.send(AppExit);
+        }
+    }
+}
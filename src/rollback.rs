@@ -0,0 +1,71 @@
+//! Registration of rolled back state
+//!
+//! Everything GGRS rolls back also has to be saved in replay viewer
+//! checkpoints and desync dumps. [`RollbackApp`] registers a type for all of
+//! them at once, so they can't drift apart.
+
+use bevy::{ecs::entity::MapEntities, prelude::*};
+use bevy_ggrs::{
+    GgrsComponentMapEntitiesPlugin, GgrsComponentSnapshotClonePlugin,
+    GgrsResourceSnapshotClonePlugin,
+};
+
+use crate::{
+    checksum::BitHash,
+    desync::DumpRegistry,
+    viewer::{ComponentCheckpointPlugin, MapEntitiesFn, ResourceCheckpointPlugin},
+};
+
+/// Registers components and resources for rollback
+pub trait RollbackApp {
+    /// Rolls back `C` on rollback entities
+    fn rollback_component<C: Component + Clone + BitHash>(&mut self) -> &mut Self;
+
+    /// Rolls back `C`, which refers to other rollback entities. They may be
+    /// respawned when rolling back, `map_entities` updates the references for
+    /// checkpoints.
+    fn rollback_component_with_map_entities<C: Component + Clone + BitHash + MapEntities>(
+        &mut self,
+        map_entities: MapEntitiesFn<C>,
+    ) -> &mut Self;
+
+    /// Rolls back the resource `R`
+    fn rollback_resource<R: Resource + Clone + BitHash>(&mut self) -> &mut Self;
+}
+
+impl RollbackApp for App {
+    fn rollback_component<C: Component + Clone + BitHash>(&mut self) -> &mut Self {
+        self.add_plugins((
+            GgrsComponentSnapshotClonePlugin::<C>::default(),
+            ComponentCheckpointPlugin::<C>::default(),
+        ));
+        dump_registry(self).add_component::<C>();
+        self
+    }
+
+    fn rollback_component_with_map_entities<C: Component + Clone + BitHash + MapEntities>(
+        &mut self,
+        map_entities: MapEntitiesFn<C>,
+    ) -> &mut Self {
+        self.add_plugins((
+            GgrsComponentSnapshotClonePlugin::<C>::default(),
+            GgrsComponentMapEntitiesPlugin::<C>::default(),
+            ComponentCheckpointPlugin::<C>::with_map_entities(map_entities),
+        ));
+        dump_registry(self).add_component::<C>();
+        self
+    }
+
+    fn rollback_resource<R: Resource + Clone + BitHash>(&mut self) -> &mut Self {
+        self.add_plugins((
+            GgrsResourceSnapshotClonePlugin::<R>::default(),
+            ResourceCheckpointPlugin::<R>::default(),
+        ));
+        dump_registry(self).add_resource::<R>();
+        self
+    }
+}
+
+fn dump_registry(app: &mut App) -> Mut<DumpRegistry> {
+    app.world.get_resource_or_insert_with(DumpRegistry::default)
+}
@@ -0,0 +1,464 @@
+//! Serializable snapshots of the rollback world
+//!
+//! A [`WorldSnapshot`] holds every rollback entity with its physics state, so
+//! a match can be restored from it on another peer. Snapshots of recent frames
+//! are kept in [`SnapshotHistory`], so one can be taken of a confirmed frame
+//! while the session is already predicting ahead.
+//!
+//! Snapshots can also be saved to disk by pressing F5 during a match, and a
+//! session can be started from one with `--load-snapshot`, to reproduce bugs
+//! from an exact state.
+
+use bevy::prelude::*;
+use bevy_ggrs::{AddRollbackCommandExtension, Rollback, RollbackOrdered, Session};
+use bevy_xpbd_2d::{math::*, prelude::*};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use crate::{
+    args::Args,
+    grabber_2d::{self, Grabber, GrabberJoint, GrabberSettings},
+    marble,
+    pause::PauseState,
+    wall, FrameCount, GgrsConfig, Marble, SessionFrameOffset, Wall,
+};
+
+pub const SNAPSHOT_MAGIC: &[u8; 4] = b"GFSS";
+pub const SNAPSHOT_VERSION: u32 = 3;
+
+/// How many frames of snapshots to keep, needs to cover the prediction window
+const SNAPSHOT_HISTORY: usize = 32;
+
+pub struct SnapshotPlugin;
+
+impl Plugin for SnapshotPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SnapshotHistory>().add_systems(
+            Update,
+            save_snapshot_keyboard
+                .run_if(crate::in_match)
+                .run_if(crate::chat::chat_unfocused)
+                .run_if(resource_exists::<Session<GgrsConfig>>()),
+        );
+    }
+}
+
+/// The rollback state of the world at a single frame
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WorldSnapshot {
+    pub frame: usize,
+    pub paused: bool,
+    pub grabber: GrabberSettings,
+    /// In rollback order, which is the same on all peers
+    pub entities: Vec<EntitySnapshot>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EntitySnapshot {
+    pub kind: EntityKind,
+    pub body: Option<BodySnapshot>,
+}
+
+/// What an entity is, decides which non-rollback components it is spawned with
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum EntityKind {
+    Wall {
+        size: [f32; 2],
+    },
+    Marble,
+    Grabber {
+        player_handle: usize,
+    },
+    GrabberJoint {
+        player_handle: usize,
+        joint: JointSnapshot,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BodySnapshot {
+    pub position: [Scalar; 2],
+    pub previous_position: [Scalar; 2],
+    /// Cosine and sine, the angle wouldn't round trip bit for bit
+    pub rotation: [Scalar; 2],
+    pub previous_rotation: [Scalar; 2],
+    pub linear_velocity: [Scalar; 2],
+    pub angular_velocity: Scalar,
+}
+
+/// A [`DistanceJoint`], with entities given as indices into the snapshot
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JointSnapshot {
+    pub entity1: usize,
+    pub entity2: usize,
+    pub local_anchor1: [Scalar; 2],
+    pub local_anchor2: [Scalar; 2],
+    pub rest_length: Scalar,
+    pub damping_linear: Scalar,
+    pub damping_angular: Scalar,
+    pub lagrange: Scalar,
+    pub compliance: Scalar,
+    pub force: [Scalar; 2],
+}
+
+/// Snapshots of recently simulated frames
+#[derive(Resource, Default)]
+pub struct SnapshotHistory(VecDeque<WorldSnapshot>);
+
+impl SnapshotHistory {
+    pub fn get(&self, frame: usize) -> Option<&WorldSnapshot> {
+        self.0.iter().find(|snapshot| snapshot.frame == frame)
+    }
+
+    pub fn latest(&self) -> Option<&WorldSnapshot> {
+        self.0.back()
+    }
+
+    pub(crate) fn insert(&mut self, snapshot: WorldSnapshot) {
+        // after a rollback, resimulated frames replace the mispredicted ones
+        self.0.retain(|s| s.frame < snapshot.frame);
+        if self.0.len() == SNAPSHOT_HISTORY {
+            self.0.pop_front();
+        }
+        self.0.push_back(snapshot);
+    }
+}
+
+#[allow(clippy::type_complexity)]
+pub(crate) type SnapshotQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Entity,
+        &'static Rollback,
+        Option<&'static Wall>,
+        Option<&'static Marble>,
+        Option<&'static Grabber>,
+        Option<&'static GrabberJoint>,
+        Option<&'static DistanceJoint>,
+        Option<&'static Position>,
+        (
+            Option<&'static PreviousPosition>,
+            Option<&'static Rotation>,
+            Option<&'static PreviousRotation>,
+            Option<&'static LinearVelocity>,
+            Option<&'static AngularVelocity>,
+        ),
+    ),
+>;
+
+fn vec2(v: Vector) -> [Scalar; 2] {
+    [v.x, v.y]
+}
+
+fn cos_sin(rotation: &Rotation) -> [Scalar; 2] {
+    [rotation.cos(), rotation.sin()]
+}
+
+/// The inverse of [`cos_sin`]
+fn rotation([cos, sin]: [Scalar; 2]) -> Rotation {
+    Rotation::from_sin_cos(sin, cos)
+}
+
+/// Captures the current rollback state of the world
+pub fn capture(
+    frame: usize,
+    pause: &PauseState,
+    grabber: &GrabberSettings,
+    order: &RollbackOrdered,
+    query: &SnapshotQuery,
+) -> WorldSnapshot {
+    let mut rollbacks: Vec<_> = query.iter().collect();
+    rollbacks.sort_by_key(|(_, rollback, ..)| order.order(**rollback));
+
+    let index_of = |entity: Entity| rollbacks.iter().position(|(e, ..)| *e == entity);
+
+    let entities = rollbacks
+        .iter()
+        .filter_map(
+            |&(_, _, wall, marble, grabber, grabber_joint, joint, position, body)| {
+                let kind = if let Some(wall) = wall {
+                    EntityKind::Wall {
+                        size: vec2(wall.size),
+                    }
+                } else if marble.is_some() {
+                    EntityKind::Marble
+                } else if let Some(grabber) = grabber {
+                    EntityKind::Grabber {
+                        player_handle: grabber.player_handle,
+                    }
+                } else if let (Some(grabber_joint), Some(joint)) = (grabber_joint, joint) {
+                    EntityKind::GrabberJoint {
+                        player_handle: grabber_joint.player_handle,
+                        joint: JointSnapshot {
+                            entity1: index_of(joint.entity1)?,
+                            entity2: index_of(joint.entity2)?,
+                            local_anchor1: vec2(joint.local_anchor1),
+                            local_anchor2: vec2(joint.local_anchor2),
+                            rest_length: joint.rest_length,
+                            damping_linear: joint.damping_linear,
+                            damping_angular: joint.damping_angular,
+                            lagrange: joint.lagrange,
+                            compliance: joint.compliance,
+                            force: vec2(joint.force),
+                        },
+                    }
+                } else {
+                    warn!("not including unknown rollback entity in snapshot");
+                    return None;
+                };
+
+                // xpbd adds the other components to bodies, but maybe not yet
+                let (previous_position, rotation, previous_rotation, linvel, angvel) = body;
+                let body = position.map(|position| {
+                    let rotation = rotation.copied().unwrap_or_default();
+                    BodySnapshot {
+                        position: vec2(position.0),
+                        previous_position: vec2(previous_position.map_or(position.0, |p| p.0)),
+                        rotation: cos_sin(&rotation),
+                        previous_rotation: cos_sin(previous_rotation.map_or(&rotation, |r| &r.0)),
+                        linear_velocity: vec2(linvel.map_or(Vector::ZERO, |v| v.0)),
+                        angular_velocity: angvel.map_or(0.0, |v| v.0),
+                    }
+                });
+
+                Some(EntitySnapshot { kind, body })
+            },
+        )
+        .collect();
+
+    WorldSnapshot {
+        frame,
+        paused: pause.paused,
+        grabber: *grabber,
+        entities,
+    }
+}
+
+/// Records a snapshot of each frame, runs at the end of frames in the rollback
+/// schedule.
+pub fn record_snapshot(
+    frame: Res<FrameCount>,
+    pause: Res<PauseState>,
+    grabber: Res<GrabberSettings>,
+    order: Res<RollbackOrdered>,
+    query: SnapshotQuery,
+    mut history: ResMut<SnapshotHistory>,
+) {
+    history.insert(capture(frame.frame, &pause, &grabber, &order, &query));
+}
+
+/// The snapshot the app was started from with `--load-snapshot`, matches are
+/// reset to it instead of the initial scene.
+#[derive(Resource, Clone, Debug)]
+pub struct StartingSnapshot(pub WorldSnapshot);
+
+impl WorldSnapshot {
+    pub fn read(reader: &mut impl Read) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != SNAPSHOT_MAGIC {
+            return Err(invalid_data("not a snapshot file"));
+        }
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        let version = u32::from_le_bytes(version);
+        if version != SNAPSHOT_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported snapshot version {version}, expected {SNAPSHOT_VERSION}"
+            )));
+        }
+        bincode::deserialize_from(reader).map_err(|e| invalid_data(e.to_string()))
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(SNAPSHOT_MAGIC)?;
+        writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+        bincode::serialize_into(writer, self).map_err(|e| invalid_data(e.to_string()))
+    }
+
+    /// Replaces all rollback entities with the ones in the snapshot, so a new
+    /// session can start from it.
+    ///
+    /// Peers that restore the same snapshot end up with the same state and
+    /// rollback order, regardless of what they simulated before. The rollback
+    /// order only ever grows, so it is started over.
+    pub fn restore(&self, commands: &mut Commands, rollbacks: &Query<Entity, With<Rollback>>) {
+        for entity in rollbacks {
+            commands.entity(entity).despawn_recursive();
+        }
+        commands.insert_resource(RollbackOrdered::default());
+
+        // spawn first, so joints can refer to entities later in the snapshot
+        let entities: Vec<_> = self
+            .entities
+            .iter()
+            .map(|_| commands.spawn_empty().add_rollback().id())
+            .collect();
+
+        for (snapshot, &entity) in self.entities.iter().zip(&entities) {
+            let position = snapshot
+                .body
+                .as_ref()
+                .map_or(Vector::ZERO, |body| body.position.into());
+
+            let mut entity_commands = commands.entity(entity);
+            match &snapshot.kind {
+                EntityKind::Wall { size } => {
+                    entity_commands.insert(wall(position, (*size).into()));
+                }
+                EntityKind::Marble => {
+                    entity_commands.insert(marble(position));
+                }
+                EntityKind::Grabber { player_handle } => {
+                    entity_commands.insert(grabber_2d::grabber(*player_handle, position));
+                }
+                EntityKind::GrabberJoint {
+                    player_handle,
+                    joint,
+                } => {
+                    let mut distance_joint =
+                        DistanceJoint::new(entities[joint.entity1], entities[joint.entity2]);
+                    distance_joint.local_anchor1 = joint.local_anchor1.into();
+                    distance_joint.local_anchor2 = joint.local_anchor2.into();
+                    distance_joint.rest_length = joint.rest_length;
+                    distance_joint.damping_linear = joint.damping_linear;
+                    distance_joint.damping_angular = joint.damping_angular;
+                    distance_joint.lagrange = joint.lagrange;
+                    distance_joint.compliance = joint.compliance;
+                    distance_joint.force = joint.force.into();
+                    entity_commands.insert((
+                        distance_joint,
+                        GrabberJoint {
+                            player_handle: *player_handle,
+                        },
+                    ));
+                }
+            }
+
+            if let Some(body) = &snapshot.body {
+                let body_rotation = rotation(body.rotation);
+                entity_commands.insert((
+                    Transform::from_translation(position.extend(0.0))
+                        .with_rotation(Quat::from_rotation_z(body_rotation.as_radians())),
+                    Position(position),
+                    PreviousPosition(body.previous_position.into()),
+                    body_rotation,
+                    PreviousRotation(rotation(body.previous_rotation)),
+                    LinearVelocity(body.linear_velocity.into()),
+                    AngularVelocity(body.angular_velocity),
+                ));
+            }
+        }
+
+        commands.insert_resource(FrameCount { frame: self.frame });
+        commands.insert_resource(PauseState {
+            paused: self.paused,
+            // recomputed from the inputs of the next frame
+            holding: 0,
+        });
+        commands.insert_resource(self.grabber);
+        commands.insert_resource(SnapshotHistory::default());
+        // the new session counts frames from zero again
+        commands.insert_resource(SessionFrameOffset(self.frame));
+    }
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Starts from the snapshot passed with `--load-snapshot`, instead of the
+/// initial scene.
+pub(crate) fn load_snapshot_file(
+    mut commands: Commands,
+    args: Res<Args>,
+    rollbacks: Query<Entity, With<Rollback>>,
+) {
+    let Some(path) = &args.load_snapshot else {
+        return;
+    };
+    let snapshot = match read_snapshot_file(path) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            error!("failed to load snapshot {path:?}: {e}");
+            std::process::exit(1);
+        }
+    };
+    info!("starting from snapshot of frame {}", snapshot.frame);
+    snapshot.restore(&mut commands, &rollbacks);
+    commands.insert_resource(StartingSnapshot(snapshot));
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn read_snapshot_file(path: &Path) -> io::Result<WorldSnapshot> {
+    WorldSnapshot::read(&mut io::BufReader::new(std::fs::File::open(path)?))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn read_snapshot_file(_path: &Path) -> io::Result<WorldSnapshot> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "no file system on wasm",
+    ))
+}
+
+/// Writes the snapshot to `snapshot-frame{frame}.gaffsnap`, returns the path
+#[cfg(not(target_arch = "wasm32"))]
+pub fn write_snapshot_file(snapshot: &WorldSnapshot) -> io::Result<String> {
+    let path = format!("snapshot-frame{}.gaffsnap", snapshot.frame);
+    let mut writer = io::BufWriter::new(std::fs::File::create(&path)?);
+    snapshot.write(&mut writer)?;
+    writer.flush()?;
+    Ok(path)
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn write_snapshot_file(_snapshot: &WorldSnapshot) -> io::Result<String> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "no file system on wasm",
+    ))
+}
+
+/// Saves the latest confirmed frame when pressing F5.
+///
+/// Predicted frames may still be rolled back, so in P2P sessions this is
+/// usually a few frames behind what's on screen.
+fn save_snapshot_keyboard(
+    keyboard: Option<Res<Input<KeyCode>>>,
+    session: Res<Session<GgrsConfig>>,
+    history: Res<SnapshotHistory>,
+    frame_offset: Res<SessionFrameOffset>,
+) {
+    if !keyboard.is_some_and(|keyboard| keyboard.just_pressed(KeyCode::F5)) {
+        return;
+    }
+
+    let snapshot = match session.as_ref() {
+        Session::P2P(session) => {
+            // the state after advancing the last confirmed frame is final
+            let confirmed = session.confirmed_frame();
+            if confirmed < 0 {
+                None
+            } else {
+                history.get(confirmed as usize + 1 + frame_offset.0)
+            }
+        }
+        // everything simulated is confirmed
+        Session::SyncTest(_) | Session::Spectator(_) => history.latest(),
+    };
+    let Some(snapshot) = snapshot else {
+        warn!("no confirmed frame to save yet");
+        return;
+    };
+
+    match write_snapshot_file(snapshot) {
+        Ok(path) => info!("saved snapshot of frame {} to {path}", snapshot.frame),
+        Err(e) => error!("failed to save snapshot: {e}"),
+    }
+}
@@ -0,0 +1,489 @@
+//! Interactive replay viewer with pause, single-step, speed control and seeking
+//!
+//! While a replay is viewed, the viewer decides how many frames to simulate
+//! each update, by feeding GGRS a manual time step. Every
+//! [`CHECKPOINT_INTERVAL`] frames, everything registered with
+//! [`crate::rollback::RollbackApp`] is saved, so seeking backwards restores
+//! the nearest checkpoint and resimulates forward from there.
+
+use bevy::{
+    ecs::schedule::ScheduleLabel,
+    prelude::*,
+    time::{TimeSystem, TimeUpdateStrategy},
+    utils::{HashMap, HashSet, Instant},
+};
+use bevy_ggrs::{AddRollbackCommandExtension, Rollback};
+use bevy_inspector_egui::bevy_egui::{egui, EguiContexts, EguiPlugin};
+use std::{collections::BTreeMap, marker::PhantomData, time::Duration};
+
+use crate::{desync::FrameDumps, replay::Playback, FrameCount};
+
+/// How often to save checkpoints, in frames
+pub const CHECKPOINT_INTERVAL: usize = 120;
+
+/// The maximum number of frames to simulate per update while seeking
+const MAX_SEEK_FRAMES_PER_UPDATE: usize = 240;
+
+/// Keyboard shortcuts and UI for the replay viewer
+pub struct ViewerPlugin;
+
+impl Plugin for ViewerPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<EguiPlugin>() {
+            app.add_plugins(EguiPlugin);
+        }
+        app.add_systems(
+            Update,
+            (viewer_keyboard, viewer_ui)
+                .run_if(resource_exists::<ReplayViewer>())
+                .run_if(resource_exists::<Playback>())
+                .run_if(crate::in_match),
+        );
+    }
+}
+
+/// Saves the state of all rollback entities
+#[derive(ScheduleLabel, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct SaveCheckpoint;
+
+/// Restores the state of all rollback entities from [`CheckpointFrame`]
+#[derive(ScheduleLabel, Clone, Debug, Hash, Eq, PartialEq)]
+pub struct LoadCheckpoint;
+
+#[derive(SystemSet, Clone, Debug, Hash, Eq, PartialEq)]
+enum LoadCheckpointSet {
+    Entities,
+    Components,
+}
+
+/// The frame being saved or loaded
+#[derive(Resource, Clone, Copy, Debug, Default)]
+pub struct CheckpointFrame(pub usize);
+
+/// The rollback entities of each checkpoint
+#[derive(Resource, Default)]
+struct EntityCheckpoints(BTreeMap<usize, Vec<Entity>>);
+
+/// Maps entities stored in checkpoints to their respawned replacements
+#[derive(Resource, Default)]
+struct CheckpointEntityMap(HashMap<Entity, Entity>);
+
+impl CheckpointEntityMap {
+    fn get(&self, entity: Entity) -> Entity {
+        self.0.get(&entity).copied().unwrap_or(entity)
+    }
+}
+
+/// Entity bookkeeping for checkpoints, and stepping and seeking through
+/// replays while there's a [`ReplayViewer`], added by [`crate::GaffPlugin`]
+pub struct CheckpointPlugin;
+
+impl Plugin for CheckpointPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<EntityCheckpoints>()
+            .init_resource::<CheckpointEntityMap>()
+            .init_resource::<CheckpointFrame>()
+            .configure_sets(
+                LoadCheckpoint,
+                (LoadCheckpointSet::Entities, LoadCheckpointSet::Components).chain(),
+            )
+            .add_systems(SaveCheckpoint, save_entities)
+            .add_systems(
+                LoadCheckpoint,
+                load_entities.in_set(LoadCheckpointSet::Entities),
+            )
+            // no UI needed, so headless apps can seek too
+            .add_systems(
+                First,
+                drive_viewer
+                    .before(TimeSystem)
+                    .run_if(resource_exists::<ReplayViewer>()),
+            );
+    }
+}
+
+fn save_entities(
+    frame: Res<CheckpointFrame>,
+    rollbacks: Query<Entity, With<Rollback>>,
+    mut checkpoints: ResMut<EntityCheckpoints>,
+) {
+    checkpoints.0.insert(frame.0, rollbacks.iter().collect());
+}
+
+fn load_entities(
+    mut commands: Commands,
+    frame: Res<CheckpointFrame>,
+    checkpoints: Res<EntityCheckpoints>,
+    mut entity_map: ResMut<CheckpointEntityMap>,
+    rollbacks: Query<Entity, With<Rollback>>,
+) {
+    let Some(entities) = checkpoints.0.get(&frame.0) else {
+        error!("no checkpoint for frame {}", frame.0);
+        return;
+    };
+
+    let mut keep = HashSet::new();
+    for &entity in entities {
+        let current = entity_map.get(entity);
+        if rollbacks.contains(current) {
+            keep.insert(current);
+        } else {
+            // despawned since the checkpoint, e.g. a released grabber
+            let respawned = commands.spawn_empty().add_rollback().id();
+            entity_map.0.insert(entity, respawned);
+            keep.insert(respawned);
+        }
+    }
+
+    for entity in &rollbacks {
+        if !keep.contains(&entity) {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+/// Replaces the entities a component refers to
+pub type MapEntitiesFn<C> = fn(&mut C, &dyn Fn(Entity) -> Entity);
+
+#[derive(Resource)]
+struct ComponentCheckpoints<C> {
+    checkpoints: BTreeMap<usize, Vec<(Entity, C)>>,
+    map_entities: Option<MapEntitiesFn<C>>,
+}
+
+/// Saves and restores `C` on rollback entities in checkpoints
+pub struct ComponentCheckpointPlugin<C> {
+    map_entities: Option<MapEntitiesFn<C>>,
+}
+
+impl<C> Default for ComponentCheckpointPlugin<C> {
+    fn default() -> Self {
+        Self { map_entities: None }
+    }
+}
+
+impl<C> ComponentCheckpointPlugin<C> {
+    /// For components that refer to other rollback entities, which may have
+    /// been respawned since the checkpoint was saved
+    pub fn with_map_entities(map_entities: MapEntitiesFn<C>) -> Self {
+        Self {
+            map_entities: Some(map_entities),
+        }
+    }
+}
+
+impl<C> ComponentCheckpointPlugin<C>
+where
+    C: Component + Clone,
+{
+    fn save(
+        frame: Res<CheckpointFrame>,
+        components: Query<(Entity, &C), With<Rollback>>,
+        mut checkpoints: ResMut<ComponentCheckpoints<C>>,
+    ) {
+        let components = components
+            .iter()
+            .map(|(entity, component)| (entity, component.clone()))
+            .collect();
+        checkpoints.checkpoints.insert(frame.0, components);
+    }
+
+    fn load(
+        mut commands: Commands,
+        frame: Res<CheckpointFrame>,
+        checkpoints: Res<ComponentCheckpoints<C>>,
+        entity_map: Res<CheckpointEntityMap>,
+        current: Query<Entity, (With<C>, With<Rollback>)>,
+    ) {
+        let Some(components) = checkpoints.checkpoints.get(&frame.0) else {
+            return;
+        };
+
+        let restored: HashSet<_> = components
+            .iter()
+            .map(|(entity, _)| entity_map.get(*entity))
+            .collect();
+        for entity in &current {
+            if !restored.contains(&entity) {
+                commands.entity(entity).remove::<C>();
+            }
+        }
+
+        for (entity, component) in components {
+            let mut component = component.clone();
+            if let Some(map_entities) = checkpoints.map_entities {
+                map_entities(&mut component, &|entity| entity_map.get(entity));
+            }
+            commands.entity(entity_map.get(*entity)).insert(component);
+        }
+    }
+}
+
+impl<C> Plugin for ComponentCheckpointPlugin<C>
+where
+    C: Component + Clone,
+{
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ComponentCheckpoints::<C> {
+            checkpoints: default(),
+            map_entities: self.map_entities,
+        })
+        .add_systems(SaveCheckpoint, Self::save)
+        .add_systems(
+            LoadCheckpoint,
+            Self::load.in_set(LoadCheckpointSet::Components),
+        );
+    }
+}
+
+#[derive(Resource)]
+struct ResourceCheckpoints<R>(BTreeMap<usize, Option<R>>);
+
+/// Saves and restores the resource `R` in checkpoints
+pub struct ResourceCheckpointPlugin<R>(PhantomData<R>);
+
+impl<R> Default for ResourceCheckpointPlugin<R> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<R> ResourceCheckpointPlugin<R>
+where
+    R: Resource + Clone,
+{
+    fn save(
+        frame: Res<CheckpointFrame>,
+        resource: Option<Res<R>>,
+        mut checkpoints: ResMut<ResourceCheckpoints<R>>,
+    ) {
+        let resource = resource.map(|resource| resource.clone());
+        checkpoints.0.insert(frame.0, resource);
+    }
+
+    fn load(
+        mut commands: Commands,
+        frame: Res<CheckpointFrame>,
+        checkpoints: Res<ResourceCheckpoints<R>>,
+    ) {
+        match checkpoints.0.get(&frame.0) {
+            Some(Some(resource)) => commands.insert_resource(resource.clone()),
+            Some(None) => commands.remove_resource::<R>(),
+            None => {}
+        }
+    }
+}
+
+impl<R> Plugin for ResourceCheckpointPlugin<R>
+where
+    R: Resource + Clone,
+{
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ResourceCheckpoints::<R>(default()))
+            .add_systems(SaveCheckpoint, Self::save)
+            .add_systems(
+                LoadCheckpoint,
+                Self::load.in_set(LoadCheckpointSet::Components),
+            );
+    }
+}
+
+/// Saves a checkpoint at the end of frames in the rollback schedule
+pub fn save_checkpoint(world: &mut World) {
+    if !world.contains_resource::<ReplayViewer>() {
+        return;
+    }
+    let frame = world.resource::<FrameCount>().frame;
+    if frame % CHECKPOINT_INTERVAL != 0
+        || world.resource::<EntityCheckpoints>().0.contains_key(&frame)
+    {
+        return;
+    }
+    world.insert_resource(CheckpointFrame(frame));
+    world.run_schedule(SaveCheckpoint);
+}
+
+/// Restores the nearest checkpoint at or before `frame`, returns its frame
+fn load_checkpoint(world: &mut World, frame: usize) -> Option<usize> {
+    let checkpoint = *world
+        .resource::<EntityCheckpoints>()
+        .0
+        .range(..=frame)
+        .next_back()?
+        .0;
+
+    world.insert_resource(CheckpointFrame(checkpoint));
+    world.run_schedule(LoadCheckpoint);
+    world.resource_mut::<Playback>().rewind(checkpoint);
+    // resimulated frames are not resimulation mismatches
+    world.resource_mut::<FrameDumps>().truncate(checkpoint);
+    Some(checkpoint)
+}
+
+/// Playback controls
+#[derive(Resource)]
+pub struct ReplayViewer {
+    pub paused: bool,
+    pub speed: f32,
+    /// Simulate a single frame while paused
+    pub step: bool,
+    /// Jump to this frame
+    pub seek: Option<usize>,
+    /// Fractional frames that are due at the current speed
+    due_frames: f64,
+    /// Total frames requested from GGRS so far
+    requested_frames: u64,
+    last_update: Option<Instant>,
+}
+
+impl Default for ReplayViewer {
+    fn default() -> Self {
+        Self {
+            paused: false,
+            speed: 1.0,
+            step: false,
+            seek: None,
+            due_frames: 0.0,
+            requested_frames: 0,
+            last_update: None,
+        }
+    }
+}
+
+/// Decides how many frames to simulate this update, and handles seeking
+fn drive_viewer(world: &mut World) {
+    let now = Instant::now();
+    let frame = world.resource::<FrameCount>().frame;
+
+    if frame == 0 && !world.resource::<EntityCheckpoints>().0.contains_key(&0) {
+        world.insert_resource(CheckpointFrame(0));
+        world.run_schedule(SaveCheckpoint);
+    }
+
+    let Some((last_frame, fps)) = world
+        .get_resource::<Playback>()
+        .map(|playback| (playback.replay().inputs.len(), playback.replay().header.fps))
+    else {
+        return;
+    };
+
+    let seek = world.resource::<ReplayViewer>().seek;
+    let mut frame = frame;
+    if let Some(target) = seek {
+        if target < frame {
+            if let Some(checkpoint) = load_checkpoint(world, target) {
+                info!("seeking to frame {target} from checkpoint {checkpoint}");
+                frame = checkpoint;
+            }
+        }
+    }
+
+    let mut viewer = world.resource_mut::<ReplayViewer>();
+    let elapsed = viewer
+        .last_update
+        .map_or(Duration::ZERO, |last_update| now - last_update);
+    viewer.last_update = Some(now);
+
+    let mut frames = match viewer.seek {
+        Some(target) => {
+            let frames = target.saturating_sub(frame).min(MAX_SEEK_FRAMES_PER_UPDATE);
+            if frame + frames >= target {
+                viewer.seek = None;
+            }
+            frames
+        }
+        None if viewer.paused => {
+            viewer.due_frames = 0.0;
+            std::mem::take(&mut viewer.step) as usize
+        }
+        None => {
+            viewer.due_frames += elapsed.as_secs_f64() * fps as f64 * viewer.speed as f64;
+            let frames = viewer.due_frames.floor();
+            viewer.due_frames -= frames;
+            frames as usize
+        }
+    };
+
+    // stop at the end of the replay
+    if frame + frames >= last_frame {
+        frames = last_frame.saturating_sub(frame);
+        viewer.paused = true;
+    }
+
+    // Feed GGRS exactly enough time for the requested frames, computed from
+    // the total so rounding errors don't add up.
+    let duration_for = |frames: u64| Duration::from_nanos(frames * 1_000_000_000 / fps as u64 + 1);
+    let previous = duration_for(viewer.requested_frames);
+    viewer.requested_frames += frames as u64;
+    let delta = duration_for(viewer.requested_frames) - previous;
+
+    world.insert_resource(TimeUpdateStrategy::ManualDuration(delta));
+}
+
+fn viewer_keyboard(
+    keyboard: Res<Input<KeyCode>>,
+    mut viewer: ResMut<ReplayViewer>,
+    frame: Res<FrameCount>,
+) {
+    if keyboard.just_pressed(KeyCode::Space) {
+        viewer.paused = !viewer.paused;
+    }
+    if keyboard.just_pressed(KeyCode::Period) {
+        viewer.paused = true;
+        viewer.step = true;
+    }
+    if keyboard.just_pressed(KeyCode::Up) {
+        viewer.speed = (viewer.speed * 2.0).min(8.0);
+    }
+    if keyboard.just_pressed(KeyCode::Down) {
+        viewer.speed = (viewer.speed / 2.0).max(0.125);
+    }
+    if keyboard.just_pressed(KeyCode::Left) {
+        viewer.seek = Some(frame.frame.saturating_sub(CHECKPOINT_INTERVAL / 2));
+    }
+    if keyboard.just_pressed(KeyCode::Right) {
+        viewer.seek = Some(frame.frame + CHECKPOINT_INTERVAL / 2);
+    }
+    if keyboard.just_pressed(KeyCode::Home) {
+        viewer.seek = Some(0);
+    }
+}
+
+fn viewer_ui(
+    mut contexts: EguiContexts,
+    mut viewer: ResMut<ReplayViewer>,
+    frame: Res<FrameCount>,
+    playback: Res<Playback>,
+) {
+    let last_frame = playback.replay().inputs.len();
+
+    egui::Window::new("Replay").show(contexts.ctx_mut(), |ui| {
+        ui.horizontal(|ui| {
+            let label = if viewer.paused { "Play" } else { "Pause" };
+            if ui.button(label).clicked() {
+                viewer.paused = !viewer.paused;
+            }
+            if ui.button("Step").clicked() {
+                viewer.paused = true;
+                viewer.step = true;
+            }
+            if ui.button("Restart").clicked() {
+                viewer.seek = Some(0);
+            }
+        });
+
+        ui.add(
+            egui::Slider::new(&mut viewer.speed, 0.125..=8.0)
+                .logarithmic(true)
+                .text("speed"),
+        );
+
+        let mut target = viewer.seek.unwrap_or(frame.frame);
+        let slider = egui::Slider::new(&mut target, 0..=last_frame).text("frame");
+        if ui.add(slider).changed() {
+            viewer.seek = Some(target);
+        }
+
+        ui.label("space: play/pause, period: step, arrows: seek and speed");
+    });
+}
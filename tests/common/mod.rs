@@ -0,0 +1,402 @@
+//! Helpers for running the simulation headless in tests.
+
+#![allow(dead_code)]
+
+use bevy::{ecs::system::SystemState, prelude::*, time::TimeUpdateStrategy, utils::HashMap};
+use bevy_gaff::{
+    checksum::Fnv1a,
+    configure_session,
+    connection::PeerConnections,
+    desync::Desynced,
+    input::*,
+    replay::{Playback, Replay},
+    snapshot::SnapshotHistory,
+    viewer::ReplayViewer,
+    FrameCount, GaffPlugin, GgrsConfig, HeadlessPlugins, SessionConfig, SessionFrameOffset,
+};
+use bevy_ggrs::{
+    ggrs::{DesyncDetection, Message, NonBlockingSocket, PlayerType},
+    LocalInputs, LocalPlayers, ReadInputs, Rollback, RollbackOrdered, Session,
+};
+use bevy_matchbox::prelude::PeerId;
+use bevy_xpbd_2d::prelude::*;
+use std::{
+    hash::Hasher,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use uuid::Uuid;
+
+/// Returns the input for a given (confirmed) frame and player handle.
+pub type InputScript = Box<dyn FnMut(usize, usize) -> GaffInput + Send + Sync>;
+
+#[derive(Resource)]
+struct ScriptedInput {
+    frame: usize,
+    script: InputScript,
+}
+
+fn scripted_input(
+    mut commands: Commands,
+    mut scripted: ResMut<ScriptedInput>,
+    local_players: Res<LocalPlayers>,
+) {
+    let frame = scripted.frame;
+    let local_inputs = local_players
+        .0
+        .iter()
+        .map(|&handle| (handle, (scripted.script)(frame, handle)))
+        .collect();
+    commands.insert_resource(LocalInputs::<GgrsConfig>(local_inputs));
+    scripted.frame += 1;
+}
+
+/// A headless app with a local [`Session::SyncTest`] for `players` players.
+///
+/// Time is advanced manually, so every update advances exactly one frame.
+pub fn synctest_app(players: usize, check_distance: usize, script: InputScript) -> App {
+    let session_config = SessionConfig::default();
+
+    let mut session_builder =
+        configure_session(players, &session_config).with_check_distance(check_distance);
+    for handle in 0..players {
+        session_builder = session_builder
+            .add_player(PlayerType::Local, handle)
+            .expect("failed to add player");
+    }
+    let session = session_builder
+        .start_synctest_session()
+        .expect("failed to start synctest session");
+
+    let mut app = headless_app(session_config, script);
+    app.insert_resource(Session::SyncTest(session));
+    app
+}
+
+/// A headless app driven by `script`, without a session
+fn headless_app(session_config: SessionConfig, script: InputScript) -> App {
+    let mut app = gaff_app(session_config);
+    app.insert_resource(ScriptedInput { frame: 0, script })
+        .add_systems(ReadInputs, scripted_input);
+    app
+}
+
+/// A headless app without input or a session
+fn gaff_app(session_config: SessionConfig) -> App {
+    let mut app = App::new();
+    app.add_plugins((
+        HeadlessPlugins,
+        GaffPlugin {
+            session: session_config,
+            local_input: false,
+            headless: true,
+            ..default()
+        },
+    ))
+    .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(
+        1.0 / session_config.fps as f64,
+    )));
+    app
+}
+
+/// A headless app playing back `replay` in the replay viewer, paused at the
+/// first frame.
+pub fn viewer_app(replay: Replay) -> App {
+    let session_config = SessionConfig::default();
+    let playback = Playback::new(replay);
+    let session = playback
+        .session_builder(&session_config)
+        .start_synctest_session()
+        .expect("failed to start playback session");
+
+    let mut app = gaff_app(session_config);
+    app.insert_resource(Session::SyncTest(session))
+        .insert_resource(playback)
+        .insert_resource(ReplayViewer {
+            paused: true,
+            ..default()
+        });
+    app
+}
+
+/// Connects [`MemorySocket`]s within the process, delivering packets instantly.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    inboxes: Arc<Mutex<HashMap<PeerId, Vec<(PeerId, Message)>>>>,
+}
+
+impl MemoryNetwork {
+    pub fn socket(&self, id: PeerId) -> MemorySocket {
+        MemorySocket {
+            id,
+            network: self.clone(),
+        }
+    }
+}
+
+/// An in-memory [`NonBlockingSocket`], for P2P sessions without signaling.
+pub struct MemorySocket {
+    id: PeerId,
+    network: MemoryNetwork,
+}
+
+impl NonBlockingSocket<PeerId> for MemorySocket {
+    fn send_to(&mut self, msg: &Message, addr: &PeerId) {
+        let mut inboxes = self.network.inboxes.lock().unwrap();
+        inboxes
+            .entry(*addr)
+            .or_default()
+            .push((self.id, msg.clone()));
+    }
+
+    fn receive_all_messages(&mut self) -> Vec<(PeerId, Message)> {
+        let mut inboxes = self.network.inboxes.lock().unwrap();
+        inboxes.remove(&self.id).unwrap_or_default()
+    }
+}
+
+/// Headless apps with [`Session::P2P`]s connected through a [`MemoryNetwork`].
+///
+/// Every step updates each app once, so they run in lockstep unless a skew is
+/// set with [`P2PHarness::with_skew`].
+pub struct P2PHarness {
+    pub apps: Vec<App>,
+    /// The number of steps each app waits before it starts updating
+    skew: Vec<usize>,
+    /// Apps that no longer update, as if they crashed
+    stopped: Vec<bool>,
+    steps: usize,
+}
+
+/// A P2P session where player `local_handle` is local, with desync detection
+/// every frame
+fn p2p_session(
+    players: usize,
+    local_handle: usize,
+    network: &MemoryNetwork,
+    session_config: &SessionConfig,
+) -> Session<GgrsConfig> {
+    let peer = |handle: usize| PeerId(Uuid::from_u128(handle as u128 + 1));
+    let mut session_builder = configure_session(players, session_config)
+        .with_desync_detection_mode(DesyncDetection::On { interval: 1 });
+    for handle in 0..players {
+        let player = if handle == local_handle {
+            PlayerType::Local
+        } else {
+            PlayerType::Remote(peer(handle))
+        };
+        session_builder = session_builder
+            .add_player(player, handle)
+            .expect("failed to add player");
+    }
+    let session = session_builder
+        .start_p2p_session(network.socket(peer(local_handle)))
+        .expect("failed to start p2p session");
+    Session::P2P(session)
+}
+
+impl P2PHarness {
+    /// One app per player, where player `handle` is local to `apps[handle]`
+    pub fn new<F>(players: usize, script: F) -> Self
+    where
+        F: Fn(usize, usize) -> GaffInput + Clone + Send + Sync + 'static,
+    {
+        let session_config = SessionConfig::default();
+        let network = MemoryNetwork::default();
+
+        let apps = (0..players)
+            .map(|local_handle| {
+                let mut app = headless_app(session_config, Box::new(script.clone()));
+                app.insert_resource(p2p_session(
+                    players,
+                    local_handle,
+                    &network,
+                    &session_config,
+                ));
+                app
+            })
+            .collect();
+
+        Self {
+            apps,
+            skew: vec![0; players],
+            stopped: vec![false; players],
+            steps: 0,
+        }
+    }
+
+    /// Delays the start of each app by the given number of steps
+    pub fn with_skew(mut self, skew: &[usize]) -> Self {
+        assert_eq!(skew.len(), self.apps.len(), "one skew per app");
+        self.skew = skew.to_vec();
+        self
+    }
+
+    /// Adds a player to the running match, the way the lobby lets peers join
+    /// late.
+    ///
+    /// Every app, including the new one, restores a snapshot of the last
+    /// confirmed frame of `apps[0]` and starts a new session with one more
+    /// player.
+    pub fn join(&mut self, script: InputScript) {
+        let session_config = SessionConfig::default();
+        let host = &self.apps[0].world;
+        let Session::P2P(session) = host.resource::<Session<GgrsConfig>>() else {
+            panic!("not a p2p session");
+        };
+        let confirmed = session.confirmed_frame();
+        assert!(confirmed >= 0, "no confirmed frame to join on");
+        // the state after advancing the last confirmed frame is final
+        let frame = confirmed as usize + 1 + host.resource::<SessionFrameOffset>().0;
+        let snapshot = host
+            .resource::<SnapshotHistory>()
+            .get(frame)
+            .unwrap_or_else(|| panic!("no snapshot of confirmed frame {frame}"))
+            .clone();
+
+        self.apps.push(headless_app(session_config, script));
+        self.skew.push(0);
+        self.stopped.push(false);
+
+        let players = self.apps.len();
+        let network = MemoryNetwork::default();
+        for (local_handle, app) in self.apps.iter_mut().enumerate() {
+            let mut state =
+                SystemState::<(Commands, Query<Entity, With<Rollback>>)>::new(&mut app.world);
+            let (mut commands, rollbacks) = state.get_mut(&mut app.world);
+            snapshot.restore(&mut commands, &rollbacks);
+            state.apply(&mut app.world);
+
+            app.insert_resource(PeerConnections::default());
+            app.insert_resource(p2p_session(
+                players,
+                local_handle,
+                &network,
+                &session_config,
+            ));
+        }
+    }
+
+    /// Stops updating an app, its peers will eventually disconnect it
+    pub fn stop(&mut self, handle: usize) {
+        self.stopped[handle] = true;
+    }
+
+    /// Updates every app that has started, panics if any of them desynced.
+    pub fn step(&mut self) {
+        for (handle, app) in self.apps.iter_mut().enumerate() {
+            if self.steps < self.skew[handle] || self.stopped[handle] {
+                continue;
+            }
+            app.update();
+            if let Some(desynced) = app.world.get_resource::<Desynced>() {
+                panic!(
+                    "player {handle} detected a desync on frame {}, dump: {:?}",
+                    desynced.frame, desynced.dump_path
+                );
+            }
+        }
+        self.steps += 1;
+    }
+
+    /// The simulated frame of each app
+    pub fn frames(&self) -> Vec<usize> {
+        self.apps
+            .iter()
+            .map(|app| app.world.resource::<FrameCount>().frame)
+            .collect()
+    }
+
+    /// Steps until every app that is not stopped has advanced to `frame`.
+    pub fn run_until(&mut self, frame: usize) {
+        // generous, apps wait for each other while synchronizing and when
+        // they get too far ahead
+        let max_steps = self.steps + 4 * frame + self.skew.iter().sum::<usize>() + 600;
+        while self
+            .frames()
+            .iter()
+            .zip(&self.stopped)
+            .any(|(&f, &stopped)| !stopped && f < frame)
+        {
+            assert!(
+                self.steps < max_steps,
+                "peers got stuck at frames {:?}",
+                self.frames()
+            );
+            self.step();
+        }
+    }
+}
+
+/// Updates the app until the simulation has advanced to `frame`.
+pub fn run_until(app: &mut App, frame: usize) {
+    while app.world.resource::<FrameCount>().frame < frame {
+        app.update();
+    }
+}
+
+/// splitmix64, so inputs only depend on the seed, frame and player
+pub fn hash(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// Pseudo-random input that is held for a while, like a human would.
+pub fn random_input(seed: u64, frame: usize, handle: usize) -> GaffInput {
+    let hold = (frame / 15) as u64;
+    let r = hash(seed ^ hash(hold ^ ((handle as u64) << 48)));
+
+    let mut buttons = (r & 0b1111) as u8 & (INPUT_UP | INPUT_DOWN | INPUT_LEFT | INPUT_RIGHT);
+    if r & (0b11 << 4) == 0 {
+        buttons |= INPUT_MOUSE_LEFT;
+    }
+
+    // mouse positions within the walls, drifting a bit every frame
+    let x = ((r >> 16) % 900) as f32 - 450.0 + (frame % 15) as f32;
+    let y = ((r >> 32) % 550) as f32 - 275.0;
+
+    GaffInput {
+        mouse_pos: Vec2::new(x, y),
+        buttons,
+        ..default()
+    }
+}
+
+/// Bit-exact hash of the physics state of every rollback entity.
+///
+/// Uses FNV-1a so the value is stable across platforms and toolchains, and
+/// visits bodies in rollback order, which unlike entity ids doesn't depend on
+/// unrelated spawns.
+pub fn physics_checksum(world: &mut World) -> u64 {
+    let mut query = world.query::<(
+        &Rollback,
+        &Position,
+        Option<&Rotation>,
+        Option<&LinearVelocity>,
+        Option<&AngularVelocity>,
+    )>();
+
+    let order = world.resource::<RollbackOrdered>();
+    let mut bodies: Vec<_> = query.iter(world).collect();
+    bodies.sort_by_key(|(rollback, ..)| order.order(**rollback));
+
+    let mut hash = Fnv1a::default();
+    for (_, position, rotation, linear_velocity, angular_velocity) in bodies {
+        hash.write_u32(position.x.to_bits());
+        hash.write_u32(position.y.to_bits());
+        if let Some(rotation) = rotation {
+            hash.write_u32(rotation.cos().to_bits());
+            hash.write_u32(rotation.sin().to_bits());
+        }
+        if let Some(linear_velocity) = linear_velocity {
+            hash.write_u32(linear_velocity.x.to_bits());
+            hash.write_u32(linear_velocity.y.to_bits());
+        }
+        if let Some(angular_velocity) = angular_velocity {
+            hash.write_u32(angular_velocity.0.to_bits());
+        }
+    }
+    hash.finish()
+}
@@ -0,0 +1,56 @@
+//! Layering of command line arguments and config files.
+//!
+//! Environment variables are tested in `config_env.rs`, as they're global to
+//! the process and these tests run in parallel.
+
+use bevy_gaff::args::Args;
+
+const CONFIG: &str = r#"
+room = "config-room"
+players = 4
+substeps = 12
+gravity = 500.0
+grab_compliance = 0.0001
+sim_latency = "50ms"
+"#;
+
+#[test]
+fn config_file_replaces_defaults() {
+    let args = Args::parse_with_config(["gaff"], CONFIG).unwrap();
+    assert_eq!(args.room.as_deref(), Some("config-room"));
+    assert_eq!(args.players, 4);
+    assert_eq!(args.substeps, 12);
+    assert_eq!(args.gravity, 500.0);
+    assert_eq!(args.grab_compliance, 0.0001);
+    // not in the file
+    assert_eq!(args.fps, 60);
+    assert_eq!(args.grab_max_distance, 100.0);
+}
+
+#[test]
+fn command_line_overrides_config_file() {
+    let args =
+        Args::parse_with_config(["gaff", "--players", "3", "--room", "cli-room"], CONFIG).unwrap();
+    assert_eq!(args.room.as_deref(), Some("cli-room"));
+    assert_eq!(args.players, 3);
+    assert_eq!(args.substeps, 12);
+}
+
+#[test]
+fn invalid_config_is_an_error() {
+    assert!(Args::parse_with_config(["gaff"], "players = \"many\"").is_err());
+    assert!(Args::parse_with_config(["gaff"], "no_such_setting = 1").is_err());
+    assert!(Args::parse_with_config(["gaff"], "sim_loss = \"lots\"").is_err());
+}
+
+#[test]
+fn config_file_is_validated_like_the_command_line() {
+    assert!(Args::parse_with_config(["gaff"], "spectate = true").is_err());
+    assert!(Args::parse_with_config(["gaff", "--room", "cli-room"], "spectate = true").is_ok());
+
+    let config = "load_snapshot = \"frame120.gaffsnap\"";
+    assert!(Args::parse_with_config(["gaff"], config).is_ok());
+    assert!(Args::parse_with_config(["gaff", "--record", "match.gaffreplay"], config).is_err());
+    let config = "load_snapshot = \"frame120.gaffsnap\"\nreplay = \"match.gaffreplay\"";
+    assert!(Args::parse_with_config(["gaff"], config).is_err());
+}
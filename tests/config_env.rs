@@ -0,0 +1,19 @@
+//! Environment variables override config files.
+//!
+//! The only test in its own binary, since the environment is global to the
+//! process and other tests parse arguments in parallel.
+
+use bevy_gaff::args::Args;
+
+#[test]
+fn environment_overrides_config_file() {
+    std::env::set_var("GAFF_MAX_PREDICTION_WINDOW", "8");
+    let config = "max_prediction_window = 16";
+    let from_env = Args::parse_with_config(["gaff"], config).unwrap();
+    let from_cli =
+        Args::parse_with_config(["gaff", "--max-prediction-window", "4"], config).unwrap();
+    std::env::remove_var("GAFF_MAX_PREDICTION_WINDOW");
+
+    assert_eq!(from_env.max_prediction_window, 8);
+    assert_eq!(from_cli.max_prediction_window, 4);
+}
@@ -0,0 +1,99 @@
+//! Golden checksum tests for catching determinism regressions.
+//!
+//! Runs the marble scene with scripted input and compares the physics state
+//! against the committed `tests/golden/marbles.txt`. If a change to the
+//! simulation is intentional, regenerate it with:
+//!
+//! ```shell
+//! GAFF_BLESS=1 cargo test --test determinism
+//! ```
+
+mod common;
+
+use bevy::prelude::*;
+use bevy_gaff::input::*;
+use common::*;
+use std::{fs, path::PathBuf};
+
+const FRAMES: usize = 600;
+const CHECKPOINT_INTERVAL: usize = 30;
+
+/// Two players poking at the marbles: grabbing, dragging, releasing and
+/// pushing them around.
+fn script(frame: usize, handle: usize) -> GaffInput {
+    let frame_f = frame as f32;
+    match (handle, frame) {
+        (0, 60..=179) => GaffInput {
+            mouse_pos: Vec2::new(frame_f - 60.0, 0.5 * (frame_f - 60.0)),
+            buttons: INPUT_MOUSE_LEFT,
+            ..default()
+        },
+        (0, 240..=299) => GaffInput {
+            buttons: INPUT_RIGHT,
+            ..default()
+        },
+        (0, 360..=419) => GaffInput {
+            mouse_pos: Vec2::new(-150.0, -250.0),
+            buttons: INPUT_MOUSE_LEFT | INPUT_LEFT,
+            ..default()
+        },
+        (1, 30..=89) => GaffInput {
+            buttons: INPUT_UP,
+            ..default()
+        },
+        (1, 120..=269) => GaffInput {
+            mouse_pos: Vec2::new(-100.0 - frame_f, -100.0),
+            buttons: INPUT_MOUSE_LEFT,
+            ..default()
+        },
+        (1, 400..=449) => GaffInput {
+            buttons: INPUT_DOWN | INPUT_RIGHT,
+            ..default()
+        },
+        _ => default(),
+    }
+}
+
+fn golden_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden/marbles.txt")
+}
+
+#[test]
+fn marbles_match_golden_checksums() {
+    let mut app = synctest_app(2, 0, Box::new(script));
+
+    let mut checksums = Vec::new();
+    for frame in (CHECKPOINT_INTERVAL..=FRAMES).step_by(CHECKPOINT_INTERVAL) {
+        run_until(&mut app, frame);
+        let checksum = physics_checksum(&mut app.world);
+        checksums.push(format!("{frame} {checksum:016x}"));
+    }
+
+    let path = golden_path();
+    if std::env::var_os("GAFF_BLESS").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, checksums.join("\n") + "\n").expect("failed to write golden file");
+        eprintln!("wrote golden checksums to {}", path.display());
+        return;
+    }
+
+    let golden = fs::read_to_string(&path).unwrap_or_else(|err| {
+        panic!(
+            "failed to read golden file {}: {err}, run with GAFF_BLESS=1 to create it",
+            path.display()
+        )
+    });
+
+    for (actual, expected) in checksums.iter().zip(golden.lines()) {
+        assert_eq!(
+            actual, expected,
+            "simulation diverged from golden checksums (frame checksum), \
+             run with GAFF_BLESS=1 if this change is intentional"
+        );
+    }
+    assert_eq!(
+        checksums.len(),
+        golden.lines().count(),
+        "golden file has a different number of checkpoints"
+    );
+}
@@ -0,0 +1,139 @@
+//! P2P sessions between several headless apps in one process.
+//!
+//! The apps are connected through in-memory sockets, and roll back whenever
+//! they mispredicted a remote player's input. Desync detection runs every
+//! frame, so any difference in the confirmed state fails the test.
+
+mod common;
+
+use bevy_gaff::{
+    connection::PeerConnections, grabber_2d::Grabber, input::*, pause::PauseState, FrameCount,
+};
+use common::*;
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+const FRAMES: usize = 600;
+
+fn run(players: usize, skew: &[usize], seed: u64) {
+    let mut harness = P2PHarness::new(players, move |frame, handle| {
+        random_input(seed, frame, handle)
+    })
+    .with_skew(skew);
+    harness.run_until(FRAMES);
+}
+
+#[test]
+fn p2p_two_players() {
+    run(2, &[0, 0], 1);
+}
+
+#[test]
+fn p2p_two_players_with_skew() {
+    run(2, &[0, 5], 2);
+}
+
+#[test]
+fn p2p_three_players_with_skew() {
+    run(3, &[0, 3, 8], 3);
+}
+
+#[test]
+fn p2p_four_players() {
+    run(4, &[0, 1, 2, 3], 4);
+}
+
+#[test]
+fn p2p_disconnect() {
+    // player 1 holds the mouse button, so it has a grabber when it leaves
+    let mut harness = P2PHarness::new(3, |frame, handle| {
+        let mut input = random_input(5, frame, handle);
+        if handle == 1 {
+            input.buttons |= INPUT_MOUSE_LEFT;
+        }
+        input
+    });
+    harness.run_until(120);
+    harness.stop(1);
+
+    // GGRS disconnects peers after a timeout in real time
+    let start = Instant::now();
+    while harness.apps[0]
+        .world
+        .resource::<PeerConnections>()
+        .disconnected
+        .is_empty()
+    {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "player 1 was never disconnected"
+        );
+        harness.step();
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    let frame = harness.apps[0].world.resource::<FrameCount>().frame;
+    harness.run_until(frame + 120);
+
+    for handle in [0, 2] {
+        let world = &mut harness.apps[handle].world;
+        let mut grabbers = world.query::<&Grabber>();
+        assert!(
+            grabbers.iter(world).all(|g| g.player_handle() != 1),
+            "the grabber of the disconnected player is still around for player {handle}"
+        );
+    }
+}
+
+#[test]
+fn p2p_late_join() {
+    // player 0 keeps grabbing and releasing, so the old peers have spawned
+    // grabbers and joints the joining peer never saw
+    let mut harness = P2PHarness::new(2, |frame, handle| {
+        let mut input = random_input(7, frame, handle);
+        input.buttons &= !INPUT_MOUSE_LEFT;
+        if handle == 0 && (frame / 20) % 2 == 0 {
+            input.buttons |= INPUT_MOUSE_LEFT;
+        }
+        input
+    });
+    harness.run_until(240);
+
+    harness.join(Box::new(|frame, handle| random_input(8, frame, handle)));
+
+    // the harness panics when any of the peers detects a desync
+    let frame = harness.frames()[0];
+    harness.run_until(frame + 240);
+}
+
+#[test]
+fn p2p_pause() {
+    // player 0 pauses for a while, player 1 keeps pushing the marbles
+    let mut harness = P2PHarness::new(2, |frame, handle| {
+        let mut input = random_input(6, frame, handle);
+        if handle == 0 && (100..200).contains(&frame) {
+            input.buttons |= INPUT_PAUSE;
+        }
+        input
+    });
+
+    harness.run_until(130);
+    let paused_checksum = physics_checksum(&mut harness.apps[0].world);
+    harness.run_until(190);
+    for app in &mut harness.apps {
+        assert!(app.world.resource::<PauseState>().paused);
+        assert_eq!(
+            physics_checksum(&mut app.world),
+            paused_checksum,
+            "physics advanced while paused"
+        );
+    }
+
+    harness.run_until(300);
+    for app in &mut harness.apps {
+        assert!(!app.world.resource::<PauseState>().paused);
+        assert_ne!(physics_checksum(&mut app.world), paused_checksum);
+    }
+}
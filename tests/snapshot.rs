@@ -0,0 +1,138 @@
+//! Saving world snapshots to disk and starting sessions from them.
+
+mod common;
+
+use bevy::{ecs::system::SystemState, prelude::*};
+use bevy_gaff::{
+    args::Args,
+    input::*,
+    snapshot::{EntityKind, SnapshotHistory, WorldSnapshot},
+    Marble,
+};
+use bevy_ggrs::{Rollback, RollbackOrdered};
+use bevy_xpbd_2d::prelude::*;
+use common::*;
+use std::fs;
+
+const SNAPSHOT_FRAME: usize = 120;
+const FRAMES: usize = 300;
+
+/// Player 0 drags a marble around while the snapshot is taken, player 1 mashes
+/// buttons.
+fn script(frame: usize, handle: usize) -> GaffInput {
+    let frame_f = frame as f32;
+    match (handle, frame) {
+        (0, 60..=179) => GaffInput {
+            mouse_pos: Vec2::new(frame_f - 60.0, 0.5 * (frame_f - 60.0)),
+            buttons: INPUT_MOUSE_LEFT,
+            ..default()
+        },
+        (0, _) => GaffInput::default(),
+        _ => random_input(7, frame, handle),
+    }
+}
+
+fn snapshot_bytes(app: &App, frame: usize) -> Vec<u8> {
+    let snapshot = app
+        .world
+        .resource::<SnapshotHistory>()
+        .get(frame)
+        .unwrap_or_else(|| panic!("no snapshot of frame {frame}"));
+    let mut bytes = Vec::new();
+    snapshot
+        .write(&mut bytes)
+        .expect("failed to write snapshot");
+    bytes
+}
+
+#[test]
+fn load_snapshot_continues_identically() {
+    let mut app = synctest_app(2, 2, Box::new(script));
+    run_until(&mut app, SNAPSHOT_FRAME);
+
+    let bytes = snapshot_bytes(&app, SNAPSHOT_FRAME);
+    let snapshot = WorldSnapshot::read(&mut &bytes[..]).expect("failed to read snapshot");
+    assert_eq!(snapshot.frame, SNAPSHOT_FRAME);
+    assert!(
+        snapshot
+            .entities
+            .iter()
+            .any(|entity| matches!(entity.kind, EntityKind::Grabber { player_handle: 0 })),
+        "the grabber of player 0 is missing from the snapshot"
+    );
+
+    let path = std::env::temp_dir().join(format!(
+        "gaff-test-{}-frame{SNAPSHOT_FRAME}.gaffsnap",
+        std::process::id()
+    ));
+    fs::write(&path, &bytes).expect("failed to write snapshot file");
+
+    // scripted inputs count frames from the start of the session
+    let mut loaded = synctest_app(
+        2,
+        2,
+        Box::new(|frame, handle| script(frame + SNAPSHOT_FRAME, handle)),
+    );
+    loaded.insert_resource(Args {
+        load_snapshot: Some(path.clone()),
+        ..default()
+    });
+    run_until(&mut loaded, FRAMES);
+    fs::remove_file(&path).ok();
+
+    run_until(&mut app, FRAMES);
+    assert!(
+        snapshot_bytes(&app, FRAMES) == snapshot_bytes(&loaded, FRAMES),
+        "the session started from the snapshot diverged"
+    );
+}
+
+/// The raw bits of every marble's rotations, in rollback order
+fn rotation_bits(world: &mut World) -> Vec<[u32; 4]> {
+    let mut query =
+        world.query_filtered::<(&Rollback, &Rotation, &PreviousRotation), With<Marble>>();
+    let order = world.resource::<RollbackOrdered>();
+    let mut marbles: Vec<_> = query
+        .iter(world)
+        .map(|(rollback, rotation, previous)| {
+            let bits = [
+                rotation.cos().to_bits(),
+                rotation.sin().to_bits(),
+                previous.0.cos().to_bits(),
+                previous.0.sin().to_bits(),
+            ];
+            (order.order(*rollback), bits)
+        })
+        .collect();
+    marbles.sort_by_key(|(order, _)| *order);
+    marbles.into_iter().map(|(_, bits)| bits).collect()
+}
+
+#[test]
+fn restore_keeps_rotation_bits() {
+    let mut app = synctest_app(2, 2, Box::new(script));
+    run_until(&mut app, SNAPSHOT_FRAME);
+
+    let latest = app
+        .world
+        .resource::<SnapshotHistory>()
+        .latest()
+        .expect("no snapshot taken");
+    let mut bytes = Vec::new();
+    latest.write(&mut bytes).expect("failed to write snapshot");
+    let snapshot = WorldSnapshot::read(&mut &bytes[..]).expect("failed to read snapshot");
+
+    let mut world = World::new();
+    let mut state = SystemState::<(Commands, Query<Entity, With<Rollback>>)>::new(&mut world);
+    let (mut commands, rollbacks) = state.get_mut(&mut world);
+    snapshot.restore(&mut commands, &rollbacks);
+    state.apply(&mut world);
+
+    let expected = rotation_bits(&mut app.world);
+    assert!(!expected.is_empty(), "no marbles to compare");
+    assert_eq!(
+        rotation_bits(&mut world),
+        expected,
+        "the restored rotations differ"
+    );
+}
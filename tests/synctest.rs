@@ -0,0 +1,49 @@
+//! Headless SyncTest sessions with pseudo-random input.
+//!
+//! SyncTest sessions roll back `check_distance` frames every frame. The
+//! resimulated frames are compared with the original ones and differences are
+//! collected in [`SyncTestMismatches`], so these tests catch state that is not
+//! properly rolled back.
+
+mod common;
+
+use bevy_gaff::desync::SyncTestMismatches;
+use common::*;
+
+const FRAMES: usize = 3000;
+
+fn assert_no_mismatch(players: usize, check_distance: usize, seed: u64) {
+    let mut app = synctest_app(
+        players,
+        check_distance,
+        Box::new(move |frame, handle| random_input(seed, frame, handle)),
+    );
+    run_until(&mut app, FRAMES);
+    let mismatches = app.world.remove_resource::<SyncTestMismatches>().unwrap();
+
+    if let Some(first) = mismatches.0.first() {
+        let types: Vec<_> = first.types.iter().map(ToString::to_string).collect();
+        panic!(
+            "{} frames resimulated differently with {players} player(s), check distance \
+             {check_distance} and seed {seed}, first on frame {}:\n  {}",
+            mismatches.0.len(),
+            first.frame,
+            types.join("\n  ")
+        );
+    }
+}
+
+#[test]
+fn synctest_one_player() {
+    assert_no_mismatch(1, 2, 1);
+}
+
+#[test]
+fn synctest_two_players() {
+    assert_no_mismatch(2, 2, 2);
+}
+
+#[test]
+fn synctest_long_check_distance() {
+    assert_no_mismatch(2, 7, 3);
+}
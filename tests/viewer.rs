@@ -0,0 +1,75 @@
+//! Seeking backwards in the replay viewer restores a checkpoint and
+//! resimulates, which has to end up in exactly the same state.
+
+mod common;
+
+use bevy::prelude::*;
+use bevy_gaff::{
+    desync::FrameDumps,
+    replay::{Replay, ReplayHeader},
+    viewer::{ReplayViewer, CHECKPOINT_INTERVAL},
+    FrameCount, GaffPlugin,
+};
+use common::*;
+
+const PLAYERS: usize = 2;
+const FRAMES: usize = 600;
+
+fn replay(seed: u64) -> Replay {
+    let plugin = GaffPlugin::default();
+    Replay {
+        header: ReplayHeader {
+            players: PLAYERS as u32,
+            fps: plugin.session.fps as u32,
+            substep_count: plugin.substep_count,
+            gravity: plugin.gravity,
+            grabber: plugin.grabber,
+        },
+        inputs: (0..FRAMES)
+            .map(|frame| {
+                (0..PLAYERS)
+                    .map(|handle| random_input(seed, frame, handle))
+                    .collect()
+            })
+            .collect(),
+        checksums: default(),
+    }
+}
+
+/// Seeks to `frame` and updates until the viewer got there
+fn seek(app: &mut App, frame: usize) {
+    app.world.resource_mut::<ReplayViewer>().seek = Some(frame);
+    for _ in 0..FRAMES {
+        app.update();
+        let viewer = app.world.resource::<ReplayViewer>();
+        if viewer.seek.is_none() && app.world.resource::<FrameCount>().frame == frame {
+            return;
+        }
+    }
+    panic!("failed to seek to frame {frame}");
+}
+
+/// The checksums of the frames that still have dumps
+fn checksums(app: &App) -> Vec<(usize, u64)> {
+    let dumps = app.world.resource::<FrameDumps>();
+    (0..=FRAMES)
+        .filter_map(|frame| Some((frame, dumps.get(frame)?.checksum())))
+        .collect()
+}
+
+#[test]
+fn seeking_back_resimulates_the_same_frames() {
+    let mut app = viewer_app(replay(4));
+
+    seek(&mut app, FRAMES);
+    let first_run = checksums(&app);
+    assert!(!first_run.is_empty());
+
+    // between checkpoints, so there's something to resimulate
+    let target = 2 * CHECKPOINT_INTERVAL + CHECKPOINT_INTERVAL / 2;
+    seek(&mut app, target);
+    assert_eq!(app.world.resource::<FrameCount>().frame, target);
+
+    seek(&mut app, FRAMES);
+    assert_eq!(checksums(&app), first_run);
+}